@@ -0,0 +1,373 @@
+use std::thread;
+
+use crate::depth::get_depth_state;
+use crate::game_state::{get_color_buffer, get_game_memory, HEIGHT, WIDTH};
+use crate::hierarchical_depth::get_hiz_size;
+use crate::math::{color_to_linear, linear_to_color};
+use crate::types::{AntiAliasing, RenderSettings, RenderTarget, ViewSettings};
+
+pub const MAX_MSAA_SAMPLES: usize = 16;
+
+// Standard D3D sample patterns, in 1/16 pixel units relative to the pixel center
+const MSAA_2X_PATTERN: [(i32, i32); 2] = [(4, 4), (-4, -4)];
+const MSAA_4X_PATTERN: [(i32, i32); 4] = [(-2, -6), (6, -2), (-6, 2), (2, 6)];
+const MSAA_8X_PATTERN: [(i32, i32); 8] = [
+    (1, -3),
+    (-1, 3),
+    (5, 1),
+    (-3, -5),
+    (-5, 5),
+    (-7, -1),
+    (3, 7),
+    (7, -7),
+];
+const MSAA_16X_PATTERN: [(i32, i32); 16] = [
+    (1, 1),
+    (-1, -3),
+    (-3, 2),
+    (4, -1),
+    (-5, -2),
+    (2, 5),
+    (5, 3),
+    (3, -5),
+    (-2, 6),
+    (0, -7),
+    (-4, -6),
+    (-6, 4),
+    (-8, 0),
+    (7, -4),
+    (6, 7),
+    (-7, -8),
+];
+
+pub fn get_msaa_sample_pattern(samples: u32) -> &'static [(i32, i32)] {
+    match samples {
+        2 => &MSAA_2X_PATTERN,
+        4 => &MSAA_4X_PATTERN,
+        8 => &MSAA_8X_PATTERN,
+        16 => &MSAA_16X_PATTERN,
+        _ => &[(0, 0)],
+    }
+}
+
+pub fn get_ssaa_scale(samples: u32) -> (u32, u32) {
+    match samples {
+        2 => (2, 1),
+        4 => (2, 2),
+        8 => (4, 2),
+        16 => (4, 4),
+        _ => (1, 1),
+    }
+}
+
+pub fn next_aa_samples(samples: u32) -> u32 {
+    match samples {
+        2 => 4,
+        4 => 8,
+        8 => 16,
+        _ => 2,
+    }
+}
+
+pub fn next_anti_aliasing(mode: AntiAliasing) -> AntiAliasing {
+    match mode {
+        AntiAliasing::None => AntiAliasing::Ssaa,
+        AntiAliasing::Ssaa => AntiAliasing::Msaa,
+        AntiAliasing::Msaa => AntiAliasing::None,
+    }
+}
+
+pub fn prepare_render_target(
+    target: &mut RenderTarget,
+    render_settings: &RenderSettings,
+    view_settings: &ViewSettings,
+) {
+    let ((scale_x, scale_y), samples) = match render_settings.anti_aliasing {
+        AntiAliasing::None => ((1, 1), 1),
+        AntiAliasing::Ssaa => (get_ssaa_scale(render_settings.aa_samples), 1),
+        AntiAliasing::Msaa => ((1, 1), render_settings.aa_samples),
+    };
+
+    if target.scale_x != scale_x || target.scale_y != scale_y || target.samples != samples {
+        target.scale_x = scale_x;
+        target.scale_y = scale_y;
+        target.samples = samples;
+        target.width = WIDTH * scale_x;
+        target.height = HEIGHT * scale_y;
+        let buffer_size = (target.width * target.height * samples) as usize;
+        target.color = vec![0; buffer_size];
+        target.depth = vec![1.0; buffer_size];
+        let (hiz_width, hiz_height) = get_hiz_size(target.width, target.height);
+        target.hiz_width = hiz_width;
+        target.hiz = vec![1.0; (hiz_width * hiz_height) as usize];
+    }
+    target.depth_state = get_depth_state(
+        render_settings.depth_format,
+        render_settings.depth_compare,
+        view_settings.z_near,
+        view_settings.z_far,
+    );
+    target.depth.fill(target.depth_state.clear_value);
+    target.hiz.fill(target.depth_state.clear_value);
+}
+
+pub fn resolve_render_target() {
+    let num_threads = thread::available_parallelism()
+        .map(|p| p.get())
+        .unwrap_or(4)
+        .min(HEIGHT as usize);
+
+    let rows_per_thread = (HEIGHT as usize + num_threads - 1) / num_threads;
+
+    thread::scope(|s| {
+        for thread_id in 0..num_threads {
+            let start_row = thread_id * rows_per_thread;
+            let end_row = std::cmp::min((thread_id + 1) * rows_per_thread, HEIGHT as usize);
+
+            s.spawn(move || {
+                let target = &get_game_memory().target;
+                let buffer = get_color_buffer();
+                let scale_x = target.scale_x as usize;
+                let scale_y = target.scale_y as usize;
+                let samples = target.samples as usize;
+                let sample_count = (scale_x * scale_y * samples) as f32;
+
+                for y in start_row..end_row {
+                    for x in 0..WIDTH as usize {
+                        // Samples are averaged as light, an sRGB average darkens the edges
+                        let mut sum = [0.0; 4];
+                        for sub_y in 0..scale_y {
+                            let row = (y * scale_y + sub_y) * target.width as usize;
+                            for sub_x in 0..scale_x {
+                                let pixel_index = (row + x * scale_x + sub_x) * samples;
+                                for color in &target.color[pixel_index..pixel_index + samples] {
+                                    let channels = color_to_linear(*color);
+                                    for channel in 0..4 {
+                                        sum[channel] += channels[channel];
+                                    }
+                                }
+                            }
+                        }
+                        buffer[y * WIDTH as usize + x] =
+                            linear_to_color(sum.map(|channel| channel / sample_count));
+                    }
+                }
+            });
+        }
+    });
+}
+
+const FXAA_EDGE_THRESHOLD_MIN: f32 = 0.0312;
+const FXAA_EDGE_THRESHOLD_MAX: f32 = 0.125;
+const FXAA_SUBPIXEL_QUALITY: f32 = 0.75;
+const FXAA_SEARCH_STEPS: [f32; 12] = [1.0, 1.0, 1.0, 1.0, 1.0, 1.5, 2.0, 2.0, 2.0, 2.0, 4.0, 8.0];
+
+fn color_luma(color: u32) -> f32 {
+    let red = ((color >> 16) & 0xFF) as f32;
+    let green = ((color >> 8) & 0xFF) as f32;
+    let blue = (color & 0xFF) as f32;
+    (0.299 * red + 0.587 * green + 0.114 * blue) / 255.0
+}
+
+fn fetch_luma(lumas: &[f32], x: i32, y: i32) -> f32 {
+    let x = x.clamp(0, WIDTH as i32 - 1) as usize;
+    let y = y.clamp(0, HEIGHT as i32 - 1) as usize;
+    lumas[y * WIDTH as usize + x]
+}
+
+fn sample_luma_bilinear(lumas: &[f32], x: f32, y: f32) -> f32 {
+    let x0 = (x - 0.5).floor();
+    let y0 = (y - 0.5).floor();
+    let tx = x - 0.5 - x0;
+    let ty = y - 0.5 - y0;
+    let top = fetch_luma(lumas, x0 as i32, y0 as i32) * (1.0 - tx)
+        + fetch_luma(lumas, x0 as i32 + 1, y0 as i32) * tx;
+    let bottom = fetch_luma(lumas, x0 as i32, y0 as i32 + 1) * (1.0 - tx)
+        + fetch_luma(lumas, x0 as i32 + 1, y0 as i32 + 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+fn sample_color_bilinear(colors: &[u32], x: f32, y: f32) -> u32 {
+    let x0 = (x - 0.5).floor();
+    let y0 = (y - 0.5).floor();
+    let tx = x - 0.5 - x0;
+    let ty = y - 0.5 - y0;
+    let fetch = |x: i32, y: i32| -> u32 {
+        let x = x.clamp(0, WIDTH as i32 - 1) as usize;
+        let y = y.clamp(0, HEIGHT as i32 - 1) as usize;
+        colors[y * WIDTH as usize + x]
+    };
+    let corners = [
+        (fetch(x0 as i32, y0 as i32), (1.0 - tx) * (1.0 - ty)),
+        (fetch(x0 as i32 + 1, y0 as i32), tx * (1.0 - ty)),
+        (fetch(x0 as i32, y0 as i32 + 1), (1.0 - tx) * ty),
+        (fetch(x0 as i32 + 1, y0 as i32 + 1), tx * ty),
+    ];
+    let mut res = 0;
+    for shift in [24, 16, 8, 0] {
+        let channel: f32 = corners
+            .iter()
+            .map(|(color, weight)| ((color >> shift) & 0xFF) as f32 * weight)
+            .sum();
+        res |= (channel.round() as u32).min(0xFF) << shift;
+    }
+    res
+}
+
+fn fxaa_pixel(colors: &[u32], lumas: &[f32], x: i32, y: i32) -> u32 {
+    let luma_center = fetch_luma(lumas, x, y);
+    let luma_north = fetch_luma(lumas, x, y - 1);
+    let luma_south = fetch_luma(lumas, x, y + 1);
+    let luma_east = fetch_luma(lumas, x + 1, y);
+    let luma_west = fetch_luma(lumas, x - 1, y);
+
+    let luma_min = luma_center
+        .min(luma_north)
+        .min(luma_south)
+        .min(luma_east)
+        .min(luma_west);
+    let luma_max = luma_center
+        .max(luma_north)
+        .max(luma_south)
+        .max(luma_east)
+        .max(luma_west);
+    let luma_range = luma_max - luma_min;
+    if luma_range < FXAA_EDGE_THRESHOLD_MIN.max(luma_max * FXAA_EDGE_THRESHOLD_MAX) {
+        return colors[(y * WIDTH as i32 + x) as usize];
+    }
+
+    let luma_north_west = fetch_luma(lumas, x - 1, y - 1);
+    let luma_north_east = fetch_luma(lumas, x + 1, y - 1);
+    let luma_south_west = fetch_luma(lumas, x - 1, y + 1);
+    let luma_south_east = fetch_luma(lumas, x + 1, y + 1);
+
+    let edge_horizontal = (luma_north_west + luma_south_west - 2.0 * luma_west).abs()
+        + 2.0 * (luma_north + luma_south - 2.0 * luma_center).abs()
+        + (luma_north_east + luma_south_east - 2.0 * luma_east).abs();
+    let edge_vertical = (luma_north_west + luma_north_east - 2.0 * luma_north).abs()
+        + 2.0 * (luma_west + luma_east - 2.0 * luma_center).abs()
+        + (luma_south_west + luma_south_east - 2.0 * luma_south).abs();
+    let is_horizontal = edge_horizontal >= edge_vertical;
+
+    let (luma_negative, luma_positive) = if is_horizontal {
+        (luma_north, luma_south)
+    } else {
+        (luma_west, luma_east)
+    };
+    let gradient_negative = luma_negative - luma_center;
+    let gradient_positive = luma_positive - luma_center;
+    let is_negative_steepest = gradient_negative.abs() >= gradient_positive.abs();
+    let gradient_scaled = 0.25 * gradient_negative.abs().max(gradient_positive.abs());
+
+    let (step, luma_local_average) = if is_negative_steepest {
+        (-1.0, 0.5 * (luma_negative + luma_center))
+    } else {
+        (1.0, 0.5 * (luma_positive + luma_center))
+    };
+
+    // Move half a pixel across the edge, then walk along it in both directions
+    let mut edge_x = x as f32 + 0.5;
+    let mut edge_y = y as f32 + 0.5;
+    let (along_x, along_y) = if is_horizontal {
+        edge_y += step * 0.5;
+        (1.0, 0.0)
+    } else {
+        edge_x += step * 0.5;
+        (0.0, 1.0)
+    };
+
+    let mut distance_negative = 0.0;
+    let mut distance_positive = 0.0;
+    let mut luma_end_negative = 0.0;
+    let mut luma_end_positive = 0.0;
+    let mut reached_negative = false;
+    let mut reached_positive = false;
+    for search_step in FXAA_SEARCH_STEPS {
+        if !reached_negative {
+            distance_negative += search_step;
+            luma_end_negative = sample_luma_bilinear(
+                lumas,
+                edge_x - along_x * distance_negative,
+                edge_y - along_y * distance_negative,
+            ) - luma_local_average;
+            reached_negative = luma_end_negative.abs() >= gradient_scaled;
+        }
+        if !reached_positive {
+            distance_positive += search_step;
+            luma_end_positive = sample_luma_bilinear(
+                lumas,
+                edge_x + along_x * distance_positive,
+                edge_y + along_y * distance_positive,
+            ) - luma_local_average;
+            reached_positive = luma_end_positive.abs() >= gradient_scaled;
+        }
+        if reached_negative && reached_positive {
+            break;
+        }
+    }
+
+    let is_negative_closer = distance_negative < distance_positive;
+    let distance_final = distance_negative.min(distance_positive);
+    let edge_length = distance_negative + distance_positive;
+    let luma_end = if is_negative_closer {
+        luma_end_negative
+    } else {
+        luma_end_positive
+    };
+    let is_center_smaller = luma_center < luma_local_average;
+    let mut pixel_offset = 0.0;
+    if (luma_end < 0.0) != is_center_smaller {
+        pixel_offset = 0.5 - distance_final / edge_length;
+    }
+
+    let luma_average = (2.0 * (luma_north + luma_south + luma_east + luma_west)
+        + luma_north_west
+        + luma_north_east
+        + luma_south_west
+        + luma_south_east)
+        / 12.0;
+    let subpixel_offset = ((luma_average - luma_center).abs() / luma_range).clamp(0.0, 1.0);
+    let subpixel_offset = (-2.0 * subpixel_offset + 3.0) * subpixel_offset * subpixel_offset;
+    pixel_offset = pixel_offset.max(subpixel_offset * subpixel_offset * FXAA_SUBPIXEL_QUALITY);
+
+    let mut sample_x = x as f32 + 0.5;
+    let mut sample_y = y as f32 + 0.5;
+    if is_horizontal {
+        sample_y += pixel_offset * step;
+    } else {
+        sample_x += pixel_offset * step;
+    }
+    sample_color_bilinear(colors, sample_x, sample_y)
+}
+
+pub fn apply_fxaa() {
+    let colors: Vec<u32> = get_color_buffer().to_vec();
+    let lumas: Vec<f32> = colors.iter().map(|color| color_luma(*color)).collect();
+
+    let num_threads = thread::available_parallelism()
+        .map(|p| p.get())
+        .unwrap_or(4)
+        .min(HEIGHT as usize);
+
+    let rows_per_thread = (HEIGHT as usize + num_threads - 1) / num_threads;
+
+    thread::scope(|s| {
+        for thread_id in 0..num_threads {
+            let start_row = thread_id * rows_per_thread;
+            let end_row = std::cmp::min((thread_id + 1) * rows_per_thread, HEIGHT as usize);
+            let colors = &colors;
+            let lumas = &lumas;
+
+            s.spawn(move || {
+                let buffer = get_color_buffer();
+
+                for y in start_row..end_row {
+                    for x in 0..WIDTH as usize {
+                        buffer[y * WIDTH as usize + x] =
+                            fxaa_pixel(colors, lumas, x as i32, y as i32);
+                    }
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,205 @@
+use crate::types::{DepthCompare, DepthFormat, DepthState};
+
+// Depth at the near and at the far plane. Linear depth and 1/w have no bound on one side, the
+// largest float stands in for it, and linear depth starts at the eye
+fn get_depth_range(format: DepthFormat) -> (f32, f32) {
+    match format {
+        DepthFormat::LinearView => (0.0, f32::MAX),
+        DepthFormat::ReciprocalW => (f32::MAX, 0.0),
+        DepthFormat::Ndc => (0.0, 1.0),
+        DepthFormat::ReverseZ => (1.0, 0.0),
+    }
+}
+
+// The value every depth passes against, so a compare mode that does not match the
+// direction of the format still draws instead of rejecting everything
+pub fn get_depth_clear_value(format: DepthFormat, compare: DepthCompare) -> f32 {
+    let (near, far) = get_depth_range(format);
+    match compare {
+        DepthCompare::Less | DepthCompare::LessEqual => near.max(far),
+        DepthCompare::Greater => near.min(far),
+        // Nothing is compared, the clear only shows where nothing was drawn
+        DepthCompare::Always => far,
+    }
+}
+
+pub fn get_default_depth_compare(format: DepthFormat) -> DepthCompare {
+    match format {
+        DepthFormat::LinearView | DepthFormat::Ndc => DepthCompare::Less,
+        DepthFormat::ReciprocalW | DepthFormat::ReverseZ => DepthCompare::Greater,
+    }
+}
+
+// Every format except linear depth is an affine function of the interpolated 1/w
+pub fn get_depth_state(
+    format: DepthFormat,
+    compare: DepthCompare,
+    z_near: f32,
+    z_far: f32,
+) -> DepthState {
+    let z_near = z_near as f64;
+    let z_far = z_far as f64;
+    let (scale, offset) = match format {
+        DepthFormat::LinearView => (0.0, 0.0),
+        DepthFormat::ReciprocalW => (1.0, 0.0),
+        // The z row of the projection matrix divided by w
+        DepthFormat::Ndc => (-z_far * z_near / (z_far - z_near), z_far / (z_far - z_near)),
+        // Near maps to 1 and far to 0, so float precision is spent on the distant range
+        DepthFormat::ReverseZ => (
+            z_far * z_near / (z_far - z_near),
+            -z_near / (z_far - z_near),
+        ),
+    };
+    DepthState {
+        format,
+        compare,
+        scale,
+        offset,
+        clear_value: get_depth_clear_value(format, compare),
+    }
+}
+
+pub fn reciprocal_w_to_depth(depth_state: &DepthState, reciprocal_w: f64) -> f32 {
+    if depth_state.format == DepthFormat::LinearView {
+        (1.0 / reciprocal_w) as f32
+    } else {
+        (depth_state.offset + depth_state.scale * reciprocal_w) as f32
+    }
+}
+
+pub fn is_depth_test_passed(compare: DepthCompare, depth: f32, stored_depth: f32) -> bool {
+    match compare {
+        DepthCompare::Less => depth < stored_depth,
+        DepthCompare::LessEqual => depth <= stored_depth,
+        DepthCompare::Greater => depth > stored_depth,
+        DepthCompare::Always => true,
+    }
+}
+
+pub fn next_depth_format(format: DepthFormat) -> DepthFormat {
+    match format {
+        DepthFormat::Ndc => DepthFormat::ReverseZ,
+        DepthFormat::ReverseZ => DepthFormat::ReciprocalW,
+        DepthFormat::ReciprocalW => DepthFormat::LinearView,
+        DepthFormat::LinearView => DepthFormat::Ndc,
+    }
+}
+
+pub fn next_depth_compare(compare: DepthCompare) -> DepthCompare {
+    match compare {
+        DepthCompare::Less => DepthCompare::LessEqual,
+        DepthCompare::LessEqual => DepthCompare::Greater,
+        DepthCompare::Greater => DepthCompare::Always,
+        DepthCompare::Always => DepthCompare::Less,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [DepthFormat; 4] = [
+        DepthFormat::LinearView,
+        DepthFormat::ReciprocalW,
+        DepthFormat::Ndc,
+        DepthFormat::ReverseZ,
+    ];
+    const COMPARES: [DepthCompare; 4] = [
+        DepthCompare::Less,
+        DepthCompare::LessEqual,
+        DepthCompare::Greater,
+        DepthCompare::Always,
+    ];
+    const Z_NEAR: f32 = 0.1;
+    const Z_FAR: f32 = 100.0;
+
+    #[test]
+    fn clear_value_passes_every_depth_between_the_planes() {
+        for format in FORMATS {
+            for compare in COMPARES {
+                let depth_state = get_depth_state(format, compare, Z_NEAR, Z_FAR);
+                for view_z in [0.11, 1.0, 50.0, 99.0] {
+                    let depth = reciprocal_w_to_depth(&depth_state, 1.0 / view_z);
+                    assert!(is_depth_test_passed(
+                        compare,
+                        depth,
+                        depth_state.clear_value
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clear_value_matches_the_format_and_compare() {
+        let expected = [
+            (DepthFormat::LinearView, [f32::MAX, f32::MAX, 0.0, f32::MAX]),
+            (DepthFormat::ReciprocalW, [f32::MAX, f32::MAX, 0.0, 0.0]),
+            (DepthFormat::Ndc, [1.0, 1.0, 0.0, 1.0]),
+            (DepthFormat::ReverseZ, [1.0, 1.0, 0.0, 0.0]),
+        ];
+        for (format, clear_values) in expected {
+            for (compare, clear_value) in COMPARES.into_iter().zip(clear_values) {
+                assert_eq!(get_depth_clear_value(format, compare), clear_value);
+            }
+        }
+    }
+
+    #[test]
+    fn always_clears_to_the_far_plane() {
+        for format in FORMATS {
+            let depth_state = get_depth_state(format, DepthCompare::Always, Z_NEAR, Z_FAR);
+            let near_depth = reciprocal_w_to_depth(&depth_state, 1.0 / Z_NEAR as f64);
+            let far_depth = reciprocal_w_to_depth(&depth_state, 1.0 / Z_FAR as f64);
+            let past_far = (depth_state.clear_value - far_depth) * (far_depth - near_depth);
+            assert!(past_far >= -1e-6);
+        }
+    }
+
+    fn get_plane_depths(format: DepthFormat) -> (f32, f32) {
+        let depth_state = get_depth_state(format, DepthCompare::Always, Z_NEAR, Z_FAR);
+        (
+            reciprocal_w_to_depth(&depth_state, 1.0 / Z_NEAR as f64),
+            reciprocal_w_to_depth(&depth_state, 1.0 / Z_FAR as f64),
+        )
+    }
+
+    #[test]
+    fn depth_state_maps_the_planes_to_the_format_range() {
+        let (near, far) = get_plane_depths(DepthFormat::Ndc);
+        assert!(near.abs() < 1e-6 && (far - 1.0).abs() < 1e-6);
+        let (near, far) = get_plane_depths(DepthFormat::ReverseZ);
+        assert!((near - 1.0).abs() < 1e-6 && far.abs() < 1e-6);
+        let (near, far) = get_plane_depths(DepthFormat::ReciprocalW);
+        assert!((near - 1.0 / Z_NEAR).abs() < 1e-4 && (far - 1.0 / Z_FAR).abs() < 1e-6);
+        let (near, far) = get_plane_depths(DepthFormat::LinearView);
+        assert!((near - Z_NEAR).abs() < 1e-6 && (far - Z_FAR).abs() < 1e-4);
+    }
+
+    #[test]
+    fn default_compare_keeps_the_nearer_depth() {
+        for format in FORMATS {
+            let compare = get_default_depth_compare(format);
+            let depth_state = get_depth_state(format, compare, Z_NEAR, Z_FAR);
+            let near_depth = reciprocal_w_to_depth(&depth_state, 1.0 / 2.0);
+            let far_depth = reciprocal_w_to_depth(&depth_state, 1.0 / 20.0);
+            assert!(is_depth_test_passed(compare, near_depth, far_depth));
+            assert!(!is_depth_test_passed(compare, far_depth, near_depth));
+        }
+    }
+
+    #[test]
+    fn depth_test_compares_against_the_stored_depth() {
+        let expected = [
+            (DepthCompare::Less, [true, false, false]),
+            (DepthCompare::LessEqual, [true, true, false]),
+            (DepthCompare::Greater, [false, false, true]),
+            (DepthCompare::Always, [true, true, true]),
+        ];
+        for (compare, results) in expected {
+            for (depth, result) in [0.25, 0.5, 0.75].into_iter().zip(results) {
+                assert_eq!(is_depth_test_passed(compare, depth, 0.5), result);
+            }
+        }
+    }
+}
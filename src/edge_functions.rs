@@ -0,0 +1,137 @@
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+pub const EDGE_LANES: usize = 4;
+
+// Edge values are integers in subpixel units; f64 keeps them exact close to the edges
+pub struct EdgeSetup {
+    pub delta_col: [f64; 3],
+    pub reciprocal_area: f64,
+    pub reciprocal_w: [f64; 3],
+    pub depth_scale: f64,
+    pub depth_offset: f64,
+    pub is_depth_linear: bool,
+}
+
+pub struct EdgeSpan {
+    pub mask: u32,
+    pub alpha: [f32; EDGE_LANES],
+    pub beta: [f32; EDGE_LANES],
+    pub gamma: [f32; EDGE_LANES],
+    pub depth: [f32; EDGE_LANES],
+}
+
+pub fn evaluate_edge_point(setup: &EdgeSetup, w: [f64; 3]) -> (f32, f32, f32, f32) {
+    let alpha = w[0] * setup.reciprocal_area;
+    let beta = w[1] * setup.reciprocal_area;
+    let gamma = w[2] * setup.reciprocal_area;
+    let reciprocal_w = setup.reciprocal_w[0] * alpha
+        + setup.reciprocal_w[1] * beta
+        + setup.reciprocal_w[2] * gamma;
+    let depth = if setup.is_depth_linear {
+        1.0 / reciprocal_w
+    } else {
+        setup.depth_offset + setup.depth_scale * reciprocal_w
+    };
+    (alpha as f32, beta as f32, gamma as f32, depth as f32)
+}
+
+pub fn evaluate_edge_span(setup: &EdgeSetup, w: [f64; 3]) -> EdgeSpan {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx") {
+            return unsafe { evaluate_edge_span_avx(setup, w) };
+        }
+    }
+    evaluate_edge_span_scalar(setup, w)
+}
+
+pub fn evaluate_edge_span_scalar(setup: &EdgeSetup, w: [f64; 3]) -> EdgeSpan {
+    let mut span = EdgeSpan {
+        mask: 0,
+        alpha: [0.0; EDGE_LANES],
+        beta: [0.0; EDGE_LANES],
+        gamma: [0.0; EDGE_LANES],
+        depth: [0.0; EDGE_LANES],
+    };
+    for lane in 0..EDGE_LANES {
+        let step = lane as f64;
+        let lane_w = [
+            w[0] + step * setup.delta_col[0],
+            w[1] + step * setup.delta_col[1],
+            w[2] + step * setup.delta_col[2],
+        ];
+        if lane_w[0] >= 0.0 && lane_w[1] >= 0.0 && lane_w[2] >= 0.0 {
+            span.mask |= 1 << lane;
+        }
+        (
+            span.alpha[lane],
+            span.beta[lane],
+            span.gamma[lane],
+            span.depth[lane],
+        ) = evaluate_edge_point(setup, lane_w);
+    }
+    span
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn evaluate_edge_span_avx(setup: &EdgeSetup, w: [f64; 3]) -> EdgeSpan {
+    let steps = _mm256_set_pd(3.0, 2.0, 1.0, 0.0);
+    let zero = _mm256_setzero_pd();
+    let reciprocal_area = _mm256_set1_pd(setup.reciprocal_area);
+
+    let w0 = _mm256_add_pd(
+        _mm256_set1_pd(w[0]),
+        _mm256_mul_pd(steps, _mm256_set1_pd(setup.delta_col[0])),
+    );
+    let w1 = _mm256_add_pd(
+        _mm256_set1_pd(w[1]),
+        _mm256_mul_pd(steps, _mm256_set1_pd(setup.delta_col[1])),
+    );
+    let w2 = _mm256_add_pd(
+        _mm256_set1_pd(w[2]),
+        _mm256_mul_pd(steps, _mm256_set1_pd(setup.delta_col[2])),
+    );
+
+    let inside = _mm256_and_pd(
+        _mm256_and_pd(
+            _mm256_cmp_pd::<_CMP_GE_OQ>(w0, zero),
+            _mm256_cmp_pd::<_CMP_GE_OQ>(w1, zero),
+        ),
+        _mm256_cmp_pd::<_CMP_GE_OQ>(w2, zero),
+    );
+
+    let alpha = _mm256_mul_pd(w0, reciprocal_area);
+    let beta = _mm256_mul_pd(w1, reciprocal_area);
+    let gamma = _mm256_mul_pd(w2, reciprocal_area);
+    // Same operation order as the scalar path so both produce identical depth
+    let reciprocal_w = _mm256_add_pd(
+        _mm256_add_pd(
+            _mm256_mul_pd(_mm256_set1_pd(setup.reciprocal_w[0]), alpha),
+            _mm256_mul_pd(_mm256_set1_pd(setup.reciprocal_w[1]), beta),
+        ),
+        _mm256_mul_pd(_mm256_set1_pd(setup.reciprocal_w[2]), gamma),
+    );
+    let depth = if setup.is_depth_linear {
+        _mm256_div_pd(_mm256_set1_pd(1.0), reciprocal_w)
+    } else {
+        _mm256_add_pd(
+            _mm256_set1_pd(setup.depth_offset),
+            _mm256_mul_pd(_mm256_set1_pd(setup.depth_scale), reciprocal_w),
+        )
+    };
+
+    let mut span = EdgeSpan {
+        mask: _mm256_movemask_pd(inside) as u32,
+        alpha: [0.0; EDGE_LANES],
+        beta: [0.0; EDGE_LANES],
+        gamma: [0.0; EDGE_LANES],
+        depth: [0.0; EDGE_LANES],
+    };
+    _mm_storeu_ps(span.alpha.as_mut_ptr(), _mm256_cvtpd_ps(alpha));
+    _mm_storeu_ps(span.beta.as_mut_ptr(), _mm256_cvtpd_ps(beta));
+    _mm_storeu_ps(span.gamma.as_mut_ptr(), _mm256_cvtpd_ps(gamma));
+    _mm_storeu_ps(span.depth.as_mut_ptr(), _mm256_cvtpd_ps(depth));
+    span
+}
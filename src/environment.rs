@@ -0,0 +1,275 @@
+use crate::game_state::get_game_memory;
+use crate::math::{color_to_linear, linear_to_color, transform_direction, vector3_length};
+use crate::matrix::{matrix4_transpose, Matrix4};
+use crate::sampler::sample_texture;
+use crate::tile_rendering::split_target_rows;
+use crate::types::{Environment, RenderTarget, Texture, TextureAddressMode, Vec3};
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+const SKY_ZENITH_COLOR: u32 = 0xFF3A6EA5;
+const SKY_HORIZON_COLOR: u32 = 0xFFB8CCE0;
+const SKY_GROUND_COLOR: u32 = 0xFF3B3631;
+
+// Gradient from the ground over the horizon to the zenith, by the elevation of the direction
+fn get_sky_color(direction: Vec3) -> [f32; 4] {
+    let zenith = color_to_linear(SKY_ZENITH_COLOR);
+    let horizon = color_to_linear(SKY_HORIZON_COLOR);
+    let ground = color_to_linear(SKY_GROUND_COLOR);
+    let elevation = direction.y / vector3_length(direction);
+    let (to, t) = if elevation >= 0.0 {
+        (zenith, elevation.sqrt())
+    } else {
+        (ground, (-elevation * 8.0).min(1.0))
+    };
+    let mut channels = horizon;
+    for (channel, to_channel) in channels.iter_mut().zip(to) {
+        *channel += (to_channel - *channel) * t;
+    }
+    channels
+}
+
+// Baked into a cube map, which is cheaper to look up every frame than a panorama
+pub fn generate_sky_cube_map() -> Environment {
+    let size = 256;
+    let faces = std::array::from_fn(|face| {
+        let mut data = Vec::with_capacity((size * size) as usize);
+        for y in 0..size {
+            for x in 0..size {
+                let s = 2.0 * (x as f32 + 0.5) / size as f32 - 1.0;
+                let t = 2.0 * (y as f32 + 0.5) / size as f32 - 1.0;
+                data.push(get_sky_color(get_cube_face_direction(face, s, t)));
+            }
+        }
+        Texture {
+            data,
+            width: size,
+            height: size,
+            address_mode_u: TextureAddressMode::ClampToEdge,
+            address_mode_v: TextureAddressMode::ClampToEdge,
+            border_color: 0xFF000000,
+        }
+    });
+    Environment::CubeMap(faces)
+}
+
+// World space direction, does not need to be normalized
+pub fn sample_environment(environment: &Environment, direction: Vec3) -> [f32; 4] {
+    match environment {
+        Environment::Panorama(texture) => {
+            let length = vector3_length(direction);
+            if length == 0.0 {
+                return sample_texture(texture, 0.5, 0.5);
+            }
+            let u = 0.5 + direction.x.atan2(direction.z) / (2.0 * PI);
+            let v = 0.5 - (direction.y / length).clamp(-1.0, 1.0).asin() / PI;
+            sample_texture(texture, u, v)
+        }
+        Environment::CubeMap(faces) => {
+            let (face, s, t, major) = get_cube_face_coords(direction);
+            if major == 0.0 {
+                return color_to_linear(faces[face].border_color);
+            }
+            sample_texture(
+                &faces[face],
+                (s / major + 1.0) * 0.5,
+                (t / major + 1.0) * 0.5,
+            )
+        }
+    }
+}
+
+// Picks the face by the largest axis, with the usual cube map orientation of each face
+fn get_cube_face_coords(direction: Vec3) -> (usize, f32, f32, f32) {
+    let Vec3 { x, y, z } = direction;
+    let (abs_x, abs_y, abs_z) = (x.abs(), y.abs(), z.abs());
+    if abs_x >= abs_y && abs_x >= abs_z {
+        if x >= 0.0 {
+            (0, -z, -y, abs_x)
+        } else {
+            (1, z, -y, abs_x)
+        }
+    } else if abs_y >= abs_z {
+        if y >= 0.0 {
+            (2, x, z, abs_y)
+        } else {
+            (3, x, -z, abs_y)
+        }
+    } else if z >= 0.0 {
+        (4, x, -y, abs_z)
+    } else {
+        (5, -x, -y, abs_z)
+    }
+}
+
+// Inverse of get_cube_face_coords for a point on the face, with s and t in -1..1
+fn get_cube_face_direction(face: usize, s: f32, t: f32) -> Vec3 {
+    let (x, y, z) = match face {
+        0 => (1.0, -t, -s),
+        1 => (-1.0, -t, s),
+        2 => (s, 1.0, t),
+        3 => (s, -1.0, -t),
+        4 => (s, -t, 1.0),
+        _ => (-s, -t, -1.0),
+    };
+    Vec3 { x, y, z }
+}
+
+// Face for a file named like "sky_px.png" or "right.png", None when the name does not tell
+pub fn get_cube_face_index(path: &Path) -> Option<usize> {
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    let names: [&[&str]; 6] = [
+        &["px", "posx", "right"],
+        &["nx", "negx", "left"],
+        &["py", "posy", "top", "up"],
+        &["ny", "negy", "bottom", "down"],
+        &["pz", "posz", "front"],
+        &["nz", "negz", "back"],
+    ];
+    names.iter().position(|face_names| {
+        face_names.iter().any(|name| {
+            stem == *name
+                || stem.ends_with(&format!("_{name}"))
+                || stem.ends_with(&format!("-{name}"))
+        })
+    })
+}
+
+// Turns target pixels back into world space directions through the camera
+#[derive(Clone, Copy)]
+pub struct ViewRays {
+    view_to_world: Matrix4,
+    half_width: f32,
+    half_height: f32,
+    scale_x: f32,
+    scale_y: f32,
+}
+
+impl ViewRays {
+    // Target coordinates, with pixel centers at .5
+    pub fn get_direction(&self, target_x: f32, target_y: f32) -> Vec3 {
+        let ndc_x = (target_x - self.half_width) / self.half_width;
+        // Screen y grows downwards while view y points up
+        let ndc_y = (self.half_height - target_y) / self.half_height;
+        transform_direction(
+            Vec3 {
+                x: ndc_x * self.scale_x,
+                y: ndc_y * self.scale_y,
+                z: 1.0,
+            },
+            self.view_to_world,
+        )
+    }
+}
+
+pub fn get_view_rays(view_matrix: Matrix4, projection_matrix: Matrix4) -> ViewRays {
+    let target = &get_game_memory().target;
+    ViewRays {
+        // The view matrix only rotates directions, so its transpose brings them back to world space
+        view_to_world: matrix4_transpose(view_matrix),
+        half_width: target.width as f32 / 2.0,
+        half_height: target.height as f32 / 2.0,
+        scale_x: 1.0 / projection_matrix.m[0][0],
+        scale_y: 1.0 / projection_matrix.m[1][1],
+    }
+}
+
+// Fills the background with the environment seen through each target pixel
+pub fn render_skybox(target: &mut RenderTarget, environment: &Environment, view_rays: &ViewRays) {
+    let width = target.width as usize;
+    let samples = target.samples as usize;
+    let rows = split_target_rows(target);
+    let num_threads = thread::available_parallelism()
+        .map(|p| p.get())
+        .unwrap_or(4)
+        .min(rows.len());
+    let rows = Mutex::new(rows.into_iter());
+
+    thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| loop {
+                let Some(row) = rows.lock().unwrap().next() else {
+                    break;
+                };
+                for target_y in row.y_min..row.y_max {
+                    let row_start = (target_y - row.y_min) as usize * width * samples;
+                    for target_x in 0..width {
+                        let direction =
+                            view_rays.get_direction(target_x as f32 + 0.5, target_y as f32 + 0.5);
+                        let [red, green, blue, _] = sample_environment(environment, direction);
+                        let color = linear_to_color([red, green, blue, 1.0]);
+                        let pixel_index = row_start + target_x * samples;
+                        row.color[pixel_index..pixel_index + samples].fill(color);
+                    }
+                }
+            });
+        }
+    });
+}
+
+pub fn next_reflectivity(reflectivity: f32) -> f32 {
+    if reflectivity < 0.25 {
+        0.25
+    } else if reflectivity < 0.5 {
+        0.5
+    } else if reflectivity < 1.0 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_directions_pick_their_face() {
+        let axes = [
+            (1.0, 0.0, 0.0),
+            (-1.0, 0.0, 0.0),
+            (0.0, 1.0, 0.0),
+            (0.0, -1.0, 0.0),
+            (0.0, 0.0, 1.0),
+            (0.0, 0.0, -1.0),
+        ];
+        for (face, (x, y, z)) in axes.into_iter().enumerate() {
+            let (coords_face, s, t, major) = get_cube_face_coords(Vec3 { x, y, z });
+            assert_eq!((coords_face, s, t, major), (face, 0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn face_direction_round_trips_through_the_face_coords() {
+        let steps = [-0.75, -0.25, 0.25, 0.75];
+        for face in 0..6 {
+            for s in steps {
+                for t in steps {
+                    let direction = get_cube_face_direction(face, s, t);
+                    let (coords_face, coords_s, coords_t, major) = get_cube_face_coords(direction);
+                    assert_eq!(coords_face, face);
+                    assert_eq!((coords_s / major, coords_t / major), (s, t));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn face_index_comes_from_the_file_name() {
+        let names = [
+            ("sky_px.png", Some(0)),
+            ("left.png", Some(1)),
+            ("Top.jpg", Some(2)),
+            ("sky-negy.png", Some(3)),
+            ("front.png", Some(4)),
+            ("sky_nz.jpg", Some(5)),
+            ("sky.png", None),
+            ("complex.png", None),
+        ];
+        for (name, face) in names {
+            assert_eq!(get_cube_face_index(Path::new(name)), face);
+        }
+    }
+}
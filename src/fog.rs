@@ -0,0 +1,155 @@
+use crate::environment::{get_view_rays, sample_environment, ViewRays};
+use crate::math::color_to_linear;
+use crate::matrix::Matrix4;
+use crate::types::{BlendMode, Environment, FogMode, ViewSettings};
+
+// Fog of the frame, applied to fragments after shading
+pub struct Fog<'a> {
+    mode: FogMode,
+    color: [f32; 4],
+    start: f32,
+    end: f32,
+    density: f32,
+    // Set when the fog takes the color of the environment behind the fragment
+    sky: Option<(&'a Environment, ViewRays)>,
+}
+
+pub fn get_frame_fog<'a>(
+    view_settings: &ViewSettings,
+    environment: &'a Environment,
+    view_matrix: Matrix4,
+    projection_matrix: Matrix4,
+) -> Option<Fog<'a>> {
+    if view_settings.fog_mode == FogMode::None {
+        return None;
+    }
+    Some(Fog {
+        mode: view_settings.fog_mode,
+        color: color_to_linear(view_settings.fog_color),
+        start: view_settings.fog_start,
+        end: view_settings.fog_end,
+        density: view_settings.fog_density,
+        sky: view_settings
+            .fog_use_sky_color
+            .then(|| (environment, get_view_rays(view_matrix, projection_matrix))),
+    })
+}
+
+// Share of the surface left after the fog at this view space depth, 1 is clear
+fn get_fog_visibility(fog: &Fog, depth: f32) -> f32 {
+    let visibility = match fog.mode {
+        FogMode::None => 1.0,
+        FogMode::Linear => {
+            if fog.end > fog.start {
+                (fog.end - depth) / (fog.end - fog.start)
+            } else if depth < fog.end {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        FogMode::Exponential => (-fog.density * depth).exp(),
+        FogMode::ExponentialSquared => (-(fog.density * depth).powi(2)).exp(),
+    };
+    visibility.clamp(0.0, 1.0)
+}
+
+// Target coordinates locate the fragment's view ray when the fog takes the sky color
+pub fn apply_fog(
+    fog: &Fog,
+    color: [f32; 4],
+    target_x: f32,
+    target_y: f32,
+    depth: f32,
+    blend_mode: BlendMode,
+) -> [f32; 4] {
+    let visibility = get_fog_visibility(fog, depth);
+    if visibility == 1.0 {
+        return color;
+    }
+    let mut fog_color = match fog.sky {
+        Some((environment, view_rays)) => {
+            sample_environment(environment, view_rays.get_direction(target_x, target_y))
+        }
+        None => fog.color,
+    };
+    // Blends that add or multiply fade toward the color that leaves the destination unchanged
+    match blend_mode {
+        BlendMode::Additive => fog_color = [0.0; 4],
+        BlendMode::Multiply => fog_color = [1.0; 4],
+        // The color already carries its alpha, so the fog it fades toward has to as well
+        BlendMode::PremultipliedAlpha => {
+            for channel in &mut fog_color[..3] {
+                *channel *= color[3];
+            }
+        }
+        BlendMode::Opaque | BlendMode::Alpha => {}
+    }
+    let mut fogged = color;
+    for (channel, fog_channel) in fogged[..3].iter_mut().zip(fog_color) {
+        *channel = fog_channel + (*channel - fog_channel) * visibility;
+    }
+    fogged
+}
+
+pub fn next_fog_mode(mode: FogMode) -> FogMode {
+    match mode {
+        FogMode::None => FogMode::Linear,
+        FogMode::Linear => FogMode::Exponential,
+        FogMode::Exponential => FogMode::ExponentialSquared,
+        FogMode::ExponentialSquared => FogMode::None,
+    }
+}
+
+pub fn scale_fog_distance(view_settings: &mut ViewSettings, scale: f32) {
+    view_settings.fog_start *= scale;
+    view_settings.fog_end *= scale;
+    view_settings.fog_density /= scale;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_fog(mode: FogMode, start: f32, end: f32) -> Fog<'static> {
+        Fog {
+            mode,
+            color: [1.0; 4],
+            start,
+            end,
+            density: 0.5,
+            sky: None,
+        }
+    }
+
+    #[test]
+    fn linear_fog_fades_between_start_and_end() {
+        let fog = get_fog(FogMode::Linear, 10.0, 20.0);
+        let visibilities =
+            [5.0, 10.0, 15.0, 20.0, 30.0].map(|depth| get_fog_visibility(&fog, depth));
+        assert_eq!(visibilities, [1.0, 1.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn linear_fog_without_a_range_cuts_off_at_the_end() {
+        let fog = get_fog(FogMode::Linear, 20.0, 20.0);
+        assert_eq!(get_fog_visibility(&fog, 19.0), 1.0);
+        assert_eq!(get_fog_visibility(&fog, 20.0), 0.0);
+    }
+
+    #[test]
+    fn exponential_fogs_follow_the_density() {
+        let fog = get_fog(FogMode::Exponential, 0.0, 0.0);
+        assert_eq!(get_fog_visibility(&fog, 0.0), 1.0);
+        assert!((get_fog_visibility(&fog, 2.0) - (-1.0f32).exp()).abs() < 1e-6);
+        let fog = get_fog(FogMode::ExponentialSquared, 0.0, 0.0);
+        assert_eq!(get_fog_visibility(&fog, 0.0), 1.0);
+        assert!((get_fog_visibility(&fog, 4.0) - (-4.0f32).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_fog_leaves_every_depth_clear() {
+        let fog = get_fog(FogMode::None, 0.0, 1.0);
+        assert_eq!(get_fog_visibility(&fog, 100.0), 1.0);
+    }
+}
@@ -1,8 +1,16 @@
+use crate::depth::get_depth_state;
+use crate::environment::generate_sky_cube_map;
+use crate::hierarchical_depth::get_hiz_size;
+use crate::math::{
+    color_to_linear, generate_planar_uvs, generate_vertex_normals, generate_vertex_tangents,
+};
 use crate::texture::REDBRICK_TEXTURE;
 use crate::types::{
-    get_vec3_identity, Camera, Entity, Memory, Mesh, Plane, RenderSettings, Texture, TextureUV,
-    Triangle, Vec2, Vec3, ViewSettings,
+    get_vec3_identity, AntiAliasing, BlendMode, Camera, DepthCompare, DepthFormat, Entity, FogMode,
+    FrameStats, Light, LightType, Material, Memory, Mesh, Plane, RenderSettings, RenderTarget,
+    ShadingMode, Texture, TextureAddressMode, TextureUV, Triangle, Vec2, Vec3, ViewSettings,
 };
+use std::sync::atomic::AtomicU32;
 
 pub static BOX_POINT_COUNTER: usize = 9 * 9 * 9;
 pub static WIDTH: u32 = 1280;
@@ -18,13 +26,28 @@ pub fn init_game_memory() {
             draw_vert: true,
             draw_edges: true,
             use_textures: false,
+            use_scanline_rasterizer: false,
             use_lighting: false,
             default_render_color: 0xFF184787,
+            anti_aliasing: AntiAliasing::None,
+            aa_samples: 4,
+            use_fxaa: false,
+            antialias_lines: false,
+            line_width: 1.0,
+            depth_test_overlays: true,
+            overlay_depth_bias: 0.001,
+            hidden_line: false,
+            use_hierarchical_depth: true,
+            subpixel_bits: 8,
+            depth_format: DepthFormat::Ndc,
+            depth_compare: DepthCompare::Less,
+            use_shadows: false,
+            shadow_map_size: 1024,
+            shadow_depth_bias: 0.02,
+            shadow_slope_bias: 1.5,
+            shadow_pcf_radius: 1,
+            use_skybox: false,
         };
-        let texture_u32: Vec<u32> = REDBRICK_TEXTURE
-            .chunks_exact(4)
-            .map(|chunk| u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
-            .collect();
         let fov_y: f32 = 3.14159265358979323846264338327950288 / 3.0;
         let aspect_ratio_x: f32 = WIDTH as f32 / HEIGHT as f32;
         let mut view_settings = ViewSettings {
@@ -37,13 +60,26 @@ pub fn init_game_memory() {
             },
             width: 1280,
             height: 720,
+            fog_mode: FogMode::None,
+            fog_color: 0xFFB8CCE0,
+            fog_use_sky_color: true,
+            fog_start: 50.0,
+            fog_end: 100.0,
+            fog_density: 0.02,
         };
         view_settings.planes =
             generate_culling_planes(view_settings.fov, view_settings.z_near, view_settings.z_far);
+        let (hiz_width, hiz_height) = get_hiz_size(WIDTH, HEIGHT);
+        let depth_state = get_depth_state(
+            render_settings.depth_format,
+            render_settings.depth_compare,
+            view_settings.z_near,
+            view_settings.z_far,
+        );
         GAME_MEMORY = Some(Memory {
             delta_time: 0.0,
             color_buffer: vec![0; (WIDTH * HEIGHT) as usize],
-            entity: generate_box(),
+            entities: vec![generate_box()],
             camera: Camera {
                 position: Vec3 {
                     x: 0.0,
@@ -59,22 +95,116 @@ pub fn init_game_memory() {
             stop: false,
             render_settings: render_settings,
 
-            light: Vec3 {
-                x: 0.0,
-                y: 0.0,
-                z: 1.0,
+            lights: get_default_lights(),
+            selected_light: 0,
+            ambient_light: 0.1,
+            environment: generate_sky_cube_map(),
+            target: RenderTarget {
+                width: WIDTH,
+                height: HEIGHT,
+                scale_x: 1,
+                scale_y: 1,
+                samples: 1,
+                color_write: true,
+                color: vec![0; (WIDTH * HEIGHT) as usize],
+                depth: vec![depth_state.clear_value; (WIDTH * HEIGHT) as usize],
+                depth_state,
+                hiz_width: hiz_width,
+                hiz: vec![depth_state.clear_value; (hiz_width * hiz_height) as usize],
             },
-            texture: Texture {
-                data: texture_u32,
-                width: 64,
-                height: 64,
-            },
-            z_buffer: vec![1.0; (WIDTH * HEIGHT) as usize],
             view_settings,
+            frame_stats: FrameStats {
+                hiz_rejected_blocks: AtomicU32::new(0),
+            },
         });
     }
 }
 
+// The sun is on, the point and spot lights wait to be switched on at runtime
+pub fn get_default_lights() -> Vec<Light> {
+    vec![
+        Light {
+            light_type: LightType::Directional,
+            enabled: true,
+            casts_shadows: true,
+            position: Vec3::default(),
+            direction: Vec3 {
+                x: 0.3,
+                y: -0.6,
+                z: 1.0,
+            },
+            color: 0xFFFFFFFF,
+            intensity: 1.0,
+            range: 0.0,
+            inner_cone: 0.0,
+            outer_cone: 0.0,
+        },
+        Light {
+            light_type: LightType::Point,
+            enabled: false,
+            casts_shadows: false,
+            position: Vec3 {
+                x: -3.0,
+                y: 3.0,
+                z: 2.0,
+            },
+            direction: Vec3::default(),
+            color: 0xFFFFC080,
+            intensity: 1.5,
+            range: 15.0,
+            inner_cone: 0.0,
+            outer_cone: 0.0,
+        },
+        Light {
+            light_type: LightType::Spot,
+            enabled: false,
+            casts_shadows: true,
+            position: Vec3 {
+                x: 0.0,
+                y: 6.0,
+                z: 5.0,
+            },
+            direction: Vec3 {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            color: 0xFF80C0FF,
+            intensity: 2.0,
+            range: 15.0,
+            inner_cone: 0.3,
+            outer_cone: 0.45,
+        },
+    ]
+}
+
+pub fn get_default_material() -> Material {
+    let texture_data: Vec<[f32; 4]> = REDBRICK_TEXTURE
+        .chunks_exact(4)
+        .map(|chunk| color_to_linear(u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])))
+        .collect();
+    Material {
+        normal_map: None,
+        shading_mode: ShadingMode::Flat,
+        specular_color: 0xFFFFFFFF,
+        shininess: 32.0,
+        reflectivity: 0.0,
+        texture: Texture {
+            data: texture_data,
+            width: 64,
+            height: 64,
+            address_mode_u: TextureAddressMode::Repeat,
+            address_mode_v: TextureAddressMode::Repeat,
+            border_color: 0xFF000000,
+        },
+        blend_mode: BlendMode::Opaque,
+        opacity: 1.0,
+        depth_write: true,
+        alpha_cutoff: 0.0,
+        uv_set: 0,
+    }
+}
+
 pub fn get_game_memory() -> &'static mut Memory {
     unsafe { GAME_MEMORY.as_mut().unwrap() }
 }
@@ -302,11 +432,17 @@ pub fn generate_box() -> Entity {
 
     let mut translation = Vec3::default();
     translation.z = 5.0;
+    let mut mesh = Mesh {
+        vertices: vertices,
+        triangles: triangles,
+        attributes: vec![],
+    };
+    mesh.attributes.push(generate_vertex_normals(&mesh));
+    mesh.attributes.push(generate_vertex_tangents(&mesh));
+    mesh.attributes.push(generate_planar_uvs(&mesh, 1));
     let entity = Entity {
-        mesh: Mesh {
-            vertices: vertices,
-            triangles: triangles,
-        },
+        mesh: mesh,
+        material: get_default_material(),
         rotation: Vec3::default(),
         scale: get_vec3_identity(),
         translation: translation,
@@ -314,6 +450,92 @@ pub fn generate_box() -> Entity {
     entity
 }
 
+// The ground plane always comes right after the model, which stays the first entity
+pub fn toggle_ground_plane(entities: &mut Vec<Entity>) {
+    if entities.len() > 1 {
+        entities.truncate(1);
+    } else {
+        entities.push(generate_ground_plane());
+    }
+}
+
+// Large quad under the box, facing up, for shadows to land on
+pub fn generate_ground_plane() -> Entity {
+    let half_size = 10.0;
+    let vertices = vec![
+        Vec3 {
+            x: -half_size,
+            y: 0.0,
+            z: -half_size,
+        },
+        Vec3 {
+            x: -half_size,
+            y: 0.0,
+            z: half_size,
+        },
+        Vec3 {
+            x: half_size,
+            y: 0.0,
+            z: half_size,
+        },
+        Vec3 {
+            x: half_size,
+            y: 0.0,
+            z: -half_size,
+        },
+    ];
+    let uv_repeat = 5.0;
+    let triangles = vec![
+        Triangle {
+            a: 1,
+            b: 2,
+            c: 3,
+            a_uv: TextureUV { u: 0.0, v: 0.0 },
+            b_uv: TextureUV {
+                u: 0.0,
+                v: uv_repeat,
+            },
+            c_uv: TextureUV {
+                u: uv_repeat,
+                v: uv_repeat,
+            },
+        },
+        Triangle {
+            a: 1,
+            b: 3,
+            c: 4,
+            a_uv: TextureUV { u: 0.0, v: 0.0 },
+            b_uv: TextureUV {
+                u: uv_repeat,
+                v: uv_repeat,
+            },
+            c_uv: TextureUV {
+                u: uv_repeat,
+                v: 0.0,
+            },
+        },
+    ];
+    let mut mesh = Mesh {
+        vertices: vertices,
+        triangles: triangles,
+        attributes: vec![],
+    };
+    mesh.attributes.push(generate_vertex_normals(&mesh));
+    mesh.attributes.push(generate_vertex_tangents(&mesh));
+    mesh.attributes.push(generate_planar_uvs(&mesh, 1));
+    Entity {
+        mesh: mesh,
+        material: get_default_material(),
+        rotation: Vec3::default(),
+        scale: get_vec3_identity(),
+        translation: Vec3 {
+            x: 0.0,
+            y: -2.0,
+            z: 5.0,
+        },
+    }
+}
+
 pub fn get_color_buffer() -> &'static mut [u32] {
     unsafe { &mut COLOR_BUFFER }
 }
@@ -0,0 +1,64 @@
+use crate::types::{DepthCompare, TargetView};
+
+pub const HIZ_BLOCK_SIZE: i32 = 8;
+
+pub fn get_hiz_size(width: u32, height: u32) -> (u32, u32) {
+    let block_size = HIZ_BLOCK_SIZE as u32;
+    (
+        (width + block_size - 1) / block_size,
+        (height + block_size - 1) / block_size,
+    )
+}
+
+// Each block keeps the depth that is easiest to pass, so a triangle that cannot beat it
+// with any of its depths fails against every sample in the block
+pub fn is_block_occluded(
+    target: &TargetView,
+    block_x: i32,
+    block_y: i32,
+    min_depth: f32,
+    max_depth: f32,
+) -> bool {
+    let block_depth = target.hiz[get_hiz_index(target, block_x, block_y)];
+    match target.depth_state.compare {
+        DepthCompare::Less => min_depth >= block_depth,
+        DepthCompare::LessEqual => min_depth > block_depth,
+        DepthCompare::Greater => max_depth <= block_depth,
+        DepthCompare::Always => false,
+    }
+}
+
+// Views start on a block row, so their blocks are never shared with another view
+fn get_hiz_index(target: &TargetView, block_x: i32, block_y: i32) -> usize {
+    let view_block_y = block_y - target.y_min / HIZ_BLOCK_SIZE;
+    (view_block_y as u32 * target.hiz_width + block_x as u32) as usize
+}
+
+pub fn update_hiz_block(target: &mut TargetView, block_x: i32, block_y: i32) {
+    let width = target.width as i32;
+    let samples = target.samples as usize;
+    let x_min = block_x * HIZ_BLOCK_SIZE;
+    let y_min = block_y * HIZ_BLOCK_SIZE;
+    let x_max = (x_min + HIZ_BLOCK_SIZE).min(width);
+    let y_max = (y_min + HIZ_BLOCK_SIZE).min(target.y_max);
+    let is_greater = target.depth_state.compare == DepthCompare::Greater;
+
+    let mut block_depth = if is_greater {
+        f32::INFINITY
+    } else {
+        f32::NEG_INFINITY
+    };
+    for y in y_min..y_max {
+        let row_start = ((y - target.y_min) * width + x_min) as usize * samples;
+        let row_end = ((y - target.y_min) * width + x_max) as usize * samples;
+        for depth in &target.depth[row_start..row_end] {
+            block_depth = if is_greater {
+                block_depth.min(*depth)
+            } else {
+                block_depth.max(*depth)
+            };
+        }
+    }
+    let hiz_index = get_hiz_index(target, block_x, block_y);
+    target.hiz[hiz_index] = block_depth;
+}
@@ -1,11 +1,22 @@
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
-use crate::game_state::get_game_memory;
-use crate::math::{vector3_add, vector3_mul_float, vector3_sub};
+use crate::anti_aliasing::{next_aa_samples, next_anti_aliasing};
+use crate::depth::{get_default_depth_compare, next_depth_compare, next_depth_format};
+use crate::environment::next_reflectivity;
+use crate::fog::{next_fog_mode, scale_fog_distance};
+use crate::game_state::{get_game_memory, toggle_ground_plane};
+use crate::lighting::next_light_type;
+use crate::math::{next_blend_mode, vector3_add, vector3_mul_float, vector3_sub};
 use crate::obj_importer::{
-    import_entity_from_obj, import_texture, open_model_path, open_texture_path,
+    import_entity_from_obj, import_environment, import_normal_map, import_texture,
+    open_environment_paths, open_model_path, open_normal_map_path, open_texture_path,
 };
+use crate::sampler::next_address_mode;
+use crate::shader::next_uv_set;
+use crate::shading::next_shading_mode;
+use crate::shadow::next_pcf_radius;
+use crate::subpixel_rendering::next_subpixel_bits;
 
 pub fn process_input(event_pump: &mut sdl2::EventPump, is_loop_running: &mut bool) {
     let memory = get_game_memory();
@@ -104,10 +115,206 @@ pub fn process_input(event_pump: &mut sdl2::EventPump, is_loop_running: &mut boo
                 keycode: Some(Keycode::Num5),
                 ..
             } => memory.render_settings.use_textures = !memory.render_settings.use_textures,
+            Event::KeyDown {
+                keycode: Some(Keycode::F1),
+                ..
+            } => {
+                memory.render_settings.use_scanline_rasterizer =
+                    !memory.render_settings.use_scanline_rasterizer
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F2),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.shading_mode = next_shading_mode(material.shading_mode);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F3),
+                ..
+            } => memory.selected_light = (memory.selected_light + 1) % memory.lights.len(),
+            Event::KeyDown {
+                keycode: Some(Keycode::F4),
+                ..
+            } => {
+                let light = &mut memory.lights[memory.selected_light];
+                light.enabled = !light.enabled;
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F5),
+                ..
+            } => {
+                let light = &mut memory.lights[memory.selected_light];
+                light.light_type = next_light_type(light.light_type);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F6),
+                ..
+            } => {
+                let light = &mut memory.lights[memory.selected_light];
+                light.intensity = (light.intensity - 0.1).max(0.0);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F7),
+                ..
+            } => memory.lights[memory.selected_light].intensity += 0.1,
+            Event::KeyDown {
+                keycode: Some(Keycode::F8),
+                ..
+            } => memory.render_settings.use_shadows = !memory.render_settings.use_shadows,
+            Event::KeyDown {
+                keycode: Some(Keycode::Semicolon),
+                ..
+            } => toggle_ground_plane(&mut memory.entities),
+            Event::KeyDown {
+                keycode: Some(Keycode::F9),
+                ..
+            } => {
+                memory.render_settings.shadow_pcf_radius =
+                    next_pcf_radius(memory.render_settings.shadow_pcf_radius)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F10),
+                ..
+            } => {
+                memory.render_settings.shadow_depth_bias =
+                    (memory.render_settings.shadow_depth_bias - 0.01).max(0.0)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                ..
+            } => memory.render_settings.shadow_depth_bias += 0.01,
+            Event::KeyDown {
+                keycode: Some(Keycode::F12),
+                ..
+            } => {
+                let light = &mut memory.lights[memory.selected_light];
+                light.casts_shadows = !light.casts_shadows;
+            }
             Event::KeyDown {
                 keycode: Some(Keycode::Num6),
                 ..
             } => memory.render_settings.use_lighting = !memory.render_settings.use_lighting,
+            Event::KeyDown {
+                keycode: Some(Keycode::Num7),
+                ..
+            } => {
+                let texture = &mut memory.entities[0].material.texture;
+                texture.address_mode_u = next_address_mode(texture.address_mode_u);
+                texture.address_mode_v = texture.address_mode_u;
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Num8),
+                ..
+            } => {
+                memory.render_settings.anti_aliasing =
+                    next_anti_aliasing(memory.render_settings.anti_aliasing)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Num9),
+                ..
+            } => {
+                memory.render_settings.aa_samples =
+                    next_aa_samples(memory.render_settings.aa_samples)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Num0),
+                ..
+            } => memory.render_settings.use_fxaa = !memory.render_settings.use_fxaa,
+            Event::KeyDown {
+                keycode: Some(Keycode::L),
+                ..
+            } => memory.render_settings.antialias_lines = !memory.render_settings.antialias_lines,
+            Event::KeyDown {
+                keycode: Some(Keycode::J),
+                ..
+            } => {
+                memory.render_settings.depth_test_overlays =
+                    !memory.render_settings.depth_test_overlays
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::H),
+                ..
+            } => memory.render_settings.hidden_line = !memory.render_settings.hidden_line,
+            Event::KeyDown {
+                keycode: Some(Keycode::X),
+                ..
+            } => {
+                memory.render_settings.use_hierarchical_depth =
+                    !memory.render_settings.use_hierarchical_depth
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::C),
+                ..
+            } => {
+                memory.render_settings.subpixel_bits =
+                    next_subpixel_bits(memory.render_settings.subpixel_bits)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::V),
+                ..
+            } => {
+                let render_settings = &mut memory.render_settings;
+                render_settings.depth_format = next_depth_format(render_settings.depth_format);
+                render_settings.depth_compare =
+                    get_default_depth_compare(render_settings.depth_format);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::R),
+                ..
+            } => {
+                memory.render_settings.depth_compare =
+                    next_depth_compare(memory.render_settings.depth_compare)
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::K),
+                ..
+            } => {
+                memory.render_settings.line_width = if memory.render_settings.line_width >= 4.0 {
+                    1.0
+                } else {
+                    memory.render_settings.line_width + 1.0
+                }
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::T),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.blend_mode = next_blend_mode(material.blend_mode);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Y),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.depth_write = !material.depth_write;
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::U),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.opacity = (material.opacity - 0.1).max(0.0);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::I),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.opacity = (material.opacity + 0.1).min(1.0);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::P),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.alpha_cutoff = if material.alpha_cutoff > 0.0 {
+                    0.0
+                } else {
+                    0.5
+                };
+            }
             Event::MouseMotion {
                 x, xrel, y, yrel, ..
             } => {
@@ -120,12 +327,68 @@ pub fn process_input(event_pump: &mut sdl2::EventPump, is_loop_running: &mut boo
             } => {
                 if let Some(path) = open_model_path() {
                     // get_game_memory().entity = import_entity_from_obj("D:\\Coding\\Projects\\graphics_3d_from_scratch_pikuma\\assets\\f22.obj",)
-                    get_game_memory().entity = import_entity_from_obj(path.to_str().unwrap_or(""));
+                    let previous_entity = std::mem::replace(
+                        &mut get_game_memory().entities[0],
+                        import_entity_from_obj(path.to_str().unwrap_or("")),
+                    );
+                    get_game_memory().entities[0].material = previous_entity.material;
                 }
                 if let Some(path) = open_texture_path() {
-                    get_game_memory().texture = import_texture(path.to_str().unwrap_or(""));
+                    let texture = &mut get_game_memory().entities[0].material.texture;
+                    let mut imported = import_texture(path.to_str().unwrap_or(""));
+                    imported.address_mode_u = texture.address_mode_u;
+                    imported.address_mode_v = texture.address_mode_v;
+                    imported.border_color = texture.border_color;
+                    *texture = imported;
+                }
+                if let Some(path) = open_normal_map_path() {
+                    get_game_memory().entities[0].material.normal_map =
+                        Some(import_normal_map(path.to_str().unwrap_or("")));
+                }
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Tab),
+                ..
+            } => memory.render_settings.use_skybox = !memory.render_settings.use_skybox,
+            Event::KeyDown {
+                keycode: Some(Keycode::Return),
+                ..
+            } => {
+                if let Some(environment) = import_environment(&open_environment_paths()) {
+                    get_game_memory().environment = environment;
                 }
             }
+            Event::KeyDown {
+                keycode: Some(Keycode::LeftBracket),
+                ..
+            } => memory.view_settings.fog_mode = next_fog_mode(memory.view_settings.fog_mode),
+            Event::KeyDown {
+                keycode: Some(Keycode::RightBracket),
+                ..
+            } => memory.view_settings.fog_use_sky_color = !memory.view_settings.fog_use_sky_color,
+            // Moves the fog closer or further, for every mode at once
+            Event::KeyDown {
+                keycode: Some(Keycode::Minus),
+                ..
+            } => scale_fog_distance(&mut memory.view_settings, 0.8),
+            Event::KeyDown {
+                keycode: Some(Keycode::Equals),
+                ..
+            } => scale_fog_distance(&mut memory.view_settings, 1.25),
+            Event::KeyDown {
+                keycode: Some(Keycode::Backslash),
+                ..
+            } => {
+                let material = &mut memory.entities[0].material;
+                material.reflectivity = next_reflectivity(material.reflectivity);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Comma),
+                ..
+            } => {
+                let entity = &mut memory.entities[0];
+                entity.material.uv_set = next_uv_set(&entity.mesh, entity.material.uv_set);
+            }
             _ => {}
         }
     }
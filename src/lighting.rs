@@ -0,0 +1,77 @@
+use crate::math::{
+    color_to_linear, transform_direction, transform_vertex, vector3_dot, vector3_length,
+    vector3_mul_float, vector3_normalize, vector3_sub,
+};
+use crate::matrix::Matrix4;
+use crate::types::{Light, LightType, Vec3};
+
+// A light moved to view space once per frame, with the intensity folded into its color
+#[derive(Clone, Copy)]
+pub struct ViewLight {
+    pub light_type: LightType,
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub radiance: [f32; 3],
+    pub range: f32,
+    pub cos_inner_cone: f32,
+    pub cos_outer_cone: f32,
+    pub casts_shadows: bool,
+    pub shadow_map: Option<usize>,
+}
+
+pub fn get_view_lights(lights: &[Light], view_matrix: Matrix4) -> Vec<ViewLight> {
+    lights
+        .iter()
+        .filter(|light| light.enabled)
+        .map(|light| {
+            let mut direction = transform_direction(light.direction, view_matrix);
+            if vector3_length(direction) > 0.0 {
+                vector3_normalize(&mut direction);
+            }
+            let [red, green, blue, _] = color_to_linear(light.color);
+            ViewLight {
+                light_type: light.light_type,
+                position: transform_vertex(light.position, view_matrix),
+                direction,
+                radiance: [red, green, blue].map(|channel| channel * light.intensity),
+                range: light.range,
+                cos_inner_cone: light.inner_cone.cos(),
+                cos_outer_cone: light.outer_cone.cos(),
+                casts_shadows: light.casts_shadows,
+                shadow_map: None,
+            }
+        })
+        .collect()
+}
+
+// Direction from the surface towards the light and how much of its radiance reaches it
+pub fn get_light_incidence(light: &ViewLight, position: Vec3) -> (Vec3, f32) {
+    if light.light_type == LightType::Directional {
+        return (vector3_mul_float(light.direction, -1.0), 1.0);
+    }
+    let mut to_light = vector3_sub(light.position, position);
+    let distance = vector3_length(to_light);
+    if distance == 0.0 {
+        return (to_light, 0.0);
+    }
+    to_light = vector3_mul_float(to_light, 1.0 / distance);
+
+    // Windowed falloff, reaches zero at the range instead of fading forever
+    let mut attenuation = (1.0 - (distance / light.range).powi(2))
+        .clamp(0.0, 1.0)
+        .powi(2);
+    if light.light_type == LightType::Spot {
+        let cos_angle = -vector3_dot(to_light, light.direction);
+        let cone_width = (light.cos_inner_cone - light.cos_outer_cone).max(f32::EPSILON);
+        attenuation *= ((cos_angle - light.cos_outer_cone) / cone_width).clamp(0.0, 1.0);
+    }
+    (to_light, attenuation)
+}
+
+pub fn next_light_type(light_type: LightType) -> LightType {
+    match light_type {
+        LightType::Directional => LightType::Point,
+        LightType::Point => LightType::Spot,
+        LightType::Spot => LightType::Directional,
+    }
+}
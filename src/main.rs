@@ -1,14 +1,27 @@
+mod anti_aliasing;
+mod depth;
+mod edge_functions;
+mod environment;
+mod fog;
 mod game_state;
+mod hierarchical_depth;
 mod input;
+mod lighting;
 mod math;
 mod matrix;
 mod obj_importer;
 mod render;
+mod sampler;
+mod shader;
+mod shading;
+mod shadow;
 mod subpixel_rendering;
 mod texture;
+mod tile_rendering;
 mod types;
 mod vector;
 
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use game_state::{get_game_memory, init_game_memory};
@@ -72,7 +85,14 @@ fn main() -> Result<(), String> {
         let time_elapsed = (current_time - fps_timer) as f64 / performance_frequency;
         if time_elapsed >= 1.0 {
             let fps = frame_count as f64 / time_elapsed;
-            println!("FPS: {:.2}", fps);
+            let hiz_rejected_blocks = get_game_memory()
+                .frame_stats
+                .hiz_rejected_blocks
+                .load(Ordering::Relaxed);
+            println!(
+                "FPS: {:.2} | Hi-Z rejected blocks: {}",
+                fps, hiz_rejected_blocks
+            );
             frame_count = 0;
             fps_timer = current_time;
         }
@@ -1,10 +1,13 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
 use crate::game_state::get_game_memory;
 use crate::matrix::{
     get_matrix4_rotation_x, get_matrix4_rotation_y, get_matrix4_rotation_z, get_matrix4_scale,
-    get_matrix4_translation, matrix4_mul_vec4, Matrix4,
+    get_matrix4_translation, matrix4_mul_matrix4, matrix4_mul_vec4, Matrix4,
 };
-use crate::types::{IntVec2, TextureUV, Vec2, Vec3};
-use crate::vector::{FixedVec4, Vec4};
+use crate::types::{BlendMode, IntVec2, Mesh, Vec2, Vec3, VertexAttribute, VertexSemantic};
+use crate::vector::Vec4;
 // pub fn ortographic_project_entity() {
 //     let memory = get_game_memory();
 //     for i in 0..BOX_POINT_COUNTER {
@@ -63,29 +66,209 @@ pub fn barycentric_weights(a: IntVec2, b: IntVec2, c: IntVec2, p: IntVec2) -> Ve
     }
 }
 
-pub fn transform_vertex(
-    vert: Vec3,
+pub fn get_model_view_matrix(
     rotation: Vec3,
     scale: Vec3,
     translation: Vec3,
     view_matrix: Matrix4,
-) -> Vec3 {
-    let mut vec4: Vec4 = vert.into();
-    vec4 = matrix4_mul_vec4(get_matrix4_scale(scale.x, scale.y, scale.z), vec4);
-    vec4 = matrix4_mul_vec4(get_matrix4_rotation_x(rotation.x), vec4);
-    vec4 = matrix4_mul_vec4(get_matrix4_rotation_y(rotation.y), vec4);
-    vec4 = matrix4_mul_vec4(get_matrix4_rotation_z(rotation.z), vec4);
-
-    vec4 = matrix4_mul_vec4(
+) -> Matrix4 {
+    let mut matrix = get_matrix4_scale(scale.x, scale.y, scale.z);
+    matrix = matrix4_mul_matrix4(get_matrix4_rotation_x(rotation.x), matrix);
+    matrix = matrix4_mul_matrix4(get_matrix4_rotation_y(rotation.y), matrix);
+    matrix = matrix4_mul_matrix4(get_matrix4_rotation_z(rotation.z), matrix);
+    matrix = matrix4_mul_matrix4(
         get_matrix4_translation(translation.x, translation.y, translation.z),
-        vec4,
+        matrix,
     );
+    matrix4_mul_matrix4(view_matrix, matrix)
+}
 
-    vec4 = matrix4_mul_vec4(view_matrix, vec4);
+pub fn transform_vertex(vert: Vec3, model_view_matrix: Matrix4) -> Vec3 {
+    let vec4: Vec4 = vert.into();
+    matrix4_mul_vec4(model_view_matrix, vec4).into()
+}
 
-    let res: Vec3 = vec4.into();
-    // res.z += 5.0;
-    res
+// Inverse transpose of the model-view for rotation and scale, keeps normals perpendicular
+pub fn get_normal_matrix(rotation: Vec3, scale: Vec3, view_matrix: Matrix4) -> Matrix4 {
+    let inverse_scale = Vec3 {
+        x: 1.0 / scale.x,
+        y: 1.0 / scale.y,
+        z: 1.0 / scale.z,
+    };
+    get_model_view_matrix(rotation, inverse_scale, Vec3::default(), view_matrix)
+}
+
+pub fn transform_direction(direction: Vec3, matrix: Matrix4) -> Vec3 {
+    let vec4 = Vec4 {
+        x: direction.x,
+        y: direction.y,
+        z: direction.z,
+        w: 0.0,
+    };
+    matrix4_mul_vec4(matrix, vec4).into()
+}
+
+// Smooth normals, each face contributes by its area through the unnormalized cross product
+pub fn generate_vertex_normals(mesh: &Mesh) -> VertexAttribute {
+    let mut normals = vec![Vec3::default(); mesh.vertices.len()];
+    let mut indices = Vec::with_capacity(mesh.triangles.len());
+    for triangle in &mesh.triangles {
+        let vertex_indices = [triangle.a, triangle.b, triangle.c].map(|index| index as usize - 1);
+        let [a, b, c] = vertex_indices.map(|index| mesh.vertices[index]);
+        let face_normal = vector3_cross(vector3_sub(b, a), vector3_sub(c, a));
+        for index in vertex_indices {
+            normals[index] = vector3_add(normals[index], face_normal);
+        }
+        indices.push(vertex_indices.map(|index| index as u32));
+    }
+
+    let mut values = Vec::with_capacity(normals.len() * 3);
+    for mut normal in normals {
+        if vector3_length(normal) > 0.0 {
+            vector3_normalize(&mut normal);
+        }
+        values.extend([normal.x, normal.y, normal.z]);
+    }
+    VertexAttribute {
+        semantic: VertexSemantic::Normal,
+        components: 3,
+        values,
+        indices,
+    }
+}
+
+// Tangents follow u and bitangents point up the image, against v which grows downward, so
+// normal maps authored with green up work as they are. The fourth component is the sign of
+// the bitangent relative to cross(normal, tangent). Corners share a tangent only when both
+// position and uv match, uv seams keep their own
+pub fn generate_vertex_tangents(mesh: &Mesh) -> VertexAttribute {
+    let normals = mesh
+        .attributes
+        .iter()
+        .find(|attribute| attribute.semantic == VertexSemantic::Normal);
+    let mut corner_lookup: HashMap<(usize, u32, u32), usize> = HashMap::new();
+    let mut tangents: Vec<Vec3> = vec![];
+    let mut bitangents: Vec<Vec3> = vec![];
+    let mut normal_sums: Vec<Vec3> = vec![];
+    let mut indices = Vec::with_capacity(mesh.triangles.len());
+    for (triangle_index, triangle) in mesh.triangles.iter().enumerate() {
+        let vertex_indices = [triangle.a, triangle.b, triangle.c].map(|index| index as usize - 1);
+        let [a, b, c] = vertex_indices.map(|index| mesh.vertices[index]);
+        let uvs = [triangle.a_uv, triangle.b_uv, triangle.c_uv];
+        let edge1 = vector3_sub(b, a);
+        let edge2 = vector3_sub(c, a);
+        let (du1, dv1) = (uvs[1].u - uvs[0].u, uvs[1].v - uvs[0].v);
+        let (du2, dv2) = (uvs[2].u - uvs[0].u, uvs[2].v - uvs[0].v);
+        let determinant = du1 * dv2 - du2 * dv1;
+        let (tangent, bitangent) = if determinant.abs() > f32::EPSILON {
+            let tangent = vector3_sub(vector3_mul_float(edge1, dv2), vector3_mul_float(edge2, dv1));
+            let bitangent =
+                vector3_sub(vector3_mul_float(edge1, du2), vector3_mul_float(edge2, du1));
+            (
+                vector3_mul_float(tangent, 1.0 / determinant),
+                vector3_mul_float(bitangent, 1.0 / determinant),
+            )
+        } else {
+            (Vec3::default(), Vec3::default())
+        };
+        let face_normal = vector3_cross(edge1, edge2);
+
+        let mut corner_indices = [0; 3];
+        for corner in 0..3 {
+            let key = (
+                vertex_indices[corner],
+                uvs[corner].u.to_bits(),
+                uvs[corner].v.to_bits(),
+            );
+            let index = *corner_lookup.entry(key).or_insert_with(|| {
+                tangents.push(Vec3::default());
+                bitangents.push(Vec3::default());
+                normal_sums.push(Vec3::default());
+                tangents.len() - 1
+            });
+            let normal = match normals {
+                Some(normals) => {
+                    let start = normals.indices[triangle_index][corner] as usize * 3;
+                    Vec3 {
+                        x: normals.values[start],
+                        y: normals.values[start + 1],
+                        z: normals.values[start + 2],
+                    }
+                }
+                None => face_normal,
+            };
+            tangents[index] = vector3_add(tangents[index], tangent);
+            bitangents[index] = vector3_add(bitangents[index], bitangent);
+            normal_sums[index] = vector3_add(normal_sums[index], normal);
+            corner_indices[corner] = index as u32;
+        }
+        indices.push(corner_indices);
+    }
+
+    let mut values = Vec::with_capacity(tangents.len() * 4);
+    for index in 0..tangents.len() {
+        let mut normal = normal_sums[index];
+        if vector3_length(normal) > 0.0 {
+            vector3_normalize(&mut normal);
+        }
+        // Gram-Schmidt, so the tangent frame stays orthogonal to the shading normal
+        let mut tangent = vector3_sub(
+            tangents[index],
+            vector3_mul_float(normal, vector3_dot(normal, tangents[index])),
+        );
+        if vector3_length(tangent) > 0.0 {
+            vector3_normalize(&mut tangent);
+        }
+        let handedness = if vector3_dot(vector3_cross(normal, tangent), bitangents[index]) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        values.extend([tangent.x, tangent.y, tangent.z, handedness]);
+    }
+    VertexAttribute {
+        semantic: VertexSemantic::Tangent,
+        components: 4,
+        values,
+        indices,
+    }
+}
+
+// Projects the mesh onto its two widest bounding box axes, so the set spans the whole mesh once
+// for textures that should not repeat. V grows downward like in the imported sets
+pub fn generate_planar_uvs(mesh: &Mesh, uv_set: u8) -> VertexAttribute {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for vertex in &mesh.vertices {
+        for (axis, value) in [vertex.x, vertex.y, vertex.z].into_iter().enumerate() {
+            min[axis] = min[axis].min(value);
+            max[axis] = max[axis].max(value);
+        }
+    }
+    let extents: [f32; 3] = std::array::from_fn(|axis| (max[axis] - min[axis]).max(f32::EPSILON));
+    let mut axes = [0, 1, 2];
+    axes.sort_by(|&a, &b| extents[b].total_cmp(&extents[a]));
+    let [u_axis, v_axis, _] = axes;
+
+    let mut values = Vec::with_capacity(mesh.vertices.len() * 2);
+    for vertex in &mesh.vertices {
+        let position = [vertex.x, vertex.y, vertex.z];
+        values.extend([
+            (position[u_axis] - min[u_axis]) / extents[u_axis],
+            1.0 - (position[v_axis] - min[v_axis]) / extents[v_axis],
+        ]);
+    }
+    let indices = mesh
+        .triangles
+        .iter()
+        .map(|triangle| [triangle.a, triangle.b, triangle.c].map(|index| index as u32 - 1))
+        .collect();
+    VertexAttribute {
+        semantic: VertexSemantic::TexCoord(uv_set),
+        components: 2,
+        values,
+        indices,
+    }
 }
 
 // pub fn line_intersection(
@@ -98,17 +281,18 @@ pub fn transform_vertex(
 
 pub fn rotate_entity() {
     let memory = get_game_memory();
-    for _i in 0..memory.entity.mesh.vertices.len() {
+    let entity = &mut memory.entities[0];
+    for _i in 0..entity.mesh.vertices.len() {
         if memory.rotation_objects_type == 0 {
-            memory.entity.rotation.x += memory.speed * memory.delta_time;
+            entity.rotation.x += memory.speed * memory.delta_time;
             // memory.entity.scale.x += memory.speed;
             // memory.entity.translation.x += memory.speed;
         } else if memory.rotation_objects_type == 1 {
-            memory.entity.rotation.y += memory.speed * memory.delta_time;
+            entity.rotation.y += memory.speed * memory.delta_time;
             // memory.entity.scale.y += memory.speed;
             // memory.entity.translation.y += memory.speed;
         } else if memory.rotation_objects_type == 2 {
-            memory.entity.rotation.z += memory.speed * memory.delta_time;
+            entity.rotation.z += memory.speed * memory.delta_time;
             // memory.entity.scale.z += memory.speed;
             // memory.entity.translation.z = 5.0;
         }
@@ -120,17 +304,109 @@ pub fn get_inv_slope(p0: IntVec2, p1: IntVec2) -> f32 {
     slope
 }
 
-pub fn light_apply_intensity(original_color: u32, percentage_factor: f32) -> u32 {
-    let alpha = original_color & 0xFF000000;
-    let red_mask = 0x00FF0000;
-    let blue_mask = 0x0000FF00;
-    let green_mask = 0x000000FF;
-    let red = ((original_color & red_mask) as f32 * percentage_factor) as u32;
-    let green = ((original_color & green_mask) as f32 * percentage_factor) as u32;
-    let blue = ((original_color & blue_mask) as f32 * percentage_factor) as u32;
+pub fn color_apply_opacity(color: [f32; 4], opacity: f32) -> [f32; 4] {
+    let [red, green, blue, alpha] = color;
+    [red, green, blue, alpha * opacity.clamp(0.0, 1.0)]
+}
 
-    let new_color = alpha | (red & red_mask) | (green & green_mask) | (blue & blue_mask);
-    new_color
+pub fn color_premultiply_alpha(color: [f32; 4]) -> [f32; 4] {
+    let [red, green, blue, alpha] = color;
+    [red * alpha, green * alpha, blue * alpha, alpha]
+}
+
+// Channels are red, green, blue and alpha in the 0..1 range, taken as they are stored
+pub fn color_to_channels(color: u32) -> [f32; 4] {
+    [
+        ((color >> 16) & 0xFF) as f32 / 255.0,
+        ((color >> 8) & 0xFF) as f32 / 255.0,
+        (color & 0xFF) as f32 / 255.0,
+        (color >> 24) as f32 / 255.0,
+    ]
+}
+
+pub fn srgb_to_linear(channel: f32) -> f32 {
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(channel: f32) -> f32 {
+    if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+static SRGB_DECODE_TABLE: LazyLock<[f32; 256]> =
+    LazyLock::new(|| std::array::from_fn(|value| srgb_to_linear(value as f32 / 255.0)));
+
+// Fine enough that the darkest steps still land on the right 8 bit value
+const SRGB_ENCODE_TABLE_SIZE: usize = 4096;
+static SRGB_ENCODE_TABLE: LazyLock<[u8; SRGB_ENCODE_TABLE_SIZE]> = LazyLock::new(|| {
+    std::array::from_fn(|index| {
+        let linear = index as f32 / (SRGB_ENCODE_TABLE_SIZE - 1) as f32;
+        (linear_to_srgb(linear) * 255.0).round() as u8
+    })
+});
+
+// Packed colors hold sRGB, the color space they are authored and displayed in. Alpha is
+// linear either way
+pub fn color_to_linear(color: u32) -> [f32; 4] {
+    [
+        SRGB_DECODE_TABLE[((color >> 16) & 0xFF) as usize],
+        SRGB_DECODE_TABLE[((color >> 8) & 0xFF) as usize],
+        SRGB_DECODE_TABLE[(color & 0xFF) as usize],
+        (color >> 24) as f32 / 255.0,
+    ]
+}
+
+pub fn linear_to_color(channels: [f32; 4]) -> u32 {
+    let table = &*SRGB_ENCODE_TABLE;
+    let [red, green, blue] = [channels[0], channels[1], channels[2]].map(|channel| {
+        let index = channel.clamp(0.0, 1.0) * (SRGB_ENCODE_TABLE_SIZE - 1) as f32 + 0.5;
+        table[index as usize] as u32
+    });
+    let alpha = (channels[3].clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    (alpha << 24) | (red << 16) | (green << 8) | blue
+}
+
+pub fn is_alpha_discarded(color: [f32; 4], alpha_cutoff: f32) -> bool {
+    color[3] < alpha_cutoff
+}
+
+// Blends a linear source over an sRGB destination and gives the result back in sRGB
+pub fn blend_colors(source: [f32; 4], destination: u32, blend_mode: BlendMode) -> u32 {
+    if blend_mode == BlendMode::Opaque {
+        return linear_to_color([source[0], source[1], source[2], 1.0]);
+    }
+    let alpha = source[3].clamp(0.0, 1.0);
+    let destination = color_to_linear(destination);
+    let mut res = [0.0, 0.0, 0.0, 1.0];
+    for channel in 0..3 {
+        let src = source[channel];
+        let dst = destination[channel];
+        res[channel] = match blend_mode {
+            BlendMode::Opaque => src,
+            BlendMode::Alpha => src * alpha + dst * (1.0 - alpha),
+            BlendMode::Additive => dst + src * alpha,
+            BlendMode::Multiply => dst + (src * dst - dst) * alpha,
+            BlendMode::PremultipliedAlpha => src + dst * (1.0 - alpha),
+        };
+    }
+    linear_to_color(res)
+}
+
+pub fn next_blend_mode(blend_mode: BlendMode) -> BlendMode {
+    match blend_mode {
+        BlendMode::Opaque => BlendMode::Alpha,
+        BlendMode::Alpha => BlendMode::Additive,
+        BlendMode::Additive => BlendMode::Multiply,
+        BlendMode::Multiply => BlendMode::PremultipliedAlpha,
+        BlendMode::PremultipliedAlpha => BlendMode::Opaque,
+    }
 }
 
 pub fn triangle_vec2_midpoint(p0: IntVec2, p1: IntVec2, p2: IntVec2) -> IntVec2 {
@@ -151,33 +427,6 @@ pub fn triangle_vec4_midpoint(p0: Vec4, p1: Vec4, p2: Vec4) -> Vec4 {
     res
 }
 
-pub fn triangle_midpoint_uv(
-    p0: Vec4,
-    p1: Vec4,
-    p2: Vec4,
-    uv0: TextureUV,
-    uv1: TextureUV,
-    uv2: TextureUV,
-) -> TextureUV {
-    let mid_point = triangle_vec4_midpoint(p0, p1, p2);
-
-    let weights = barycentric_weights(p0.into(), p1.into(), p2.into(), mid_point.into());
-
-    let alpha = weights.x;
-    let beta = weights.y;
-    let gamma = weights.z;
-
-    let interpolated_reciprocal_w = 1.0 / p0.w * alpha + 1.0 / p1.w * beta + 1.0 / p2.w * gamma;
-
-    let mut texture = TextureUV {
-        u: uv0.u * alpha / p0.w + uv1.u / p1.w * beta + uv2.u / p2.w * weights.z,
-        v: uv0.v / p0.w * weights.x + uv1.v / p1.w * weights.y + uv2.v / p2.w * weights.z,
-    };
-    texture.u /= interpolated_reciprocal_w;
-    texture.v /= interpolated_reciprocal_w;
-    texture
-}
-
 #[allow(dead_code)]
 pub fn rotate_vec3_x(vec: Vec3, angle: f32) -> Vec3 {
     let mut res = Vec3::default();
@@ -257,6 +506,7 @@ pub fn vector3_sub(a: Vec3, b: Vec3) -> Vec3 {
     res
 }
 
+#[allow(dead_code)]
 pub fn vector2_sub(a: Vec2, b: Vec2) -> Vec2 {
     let res = Vec2 {
         x: a.x - b.x,
@@ -352,3 +602,57 @@ pub fn vector3_normalize(a: &mut Vec3) {
     a.y = a.y / length;
     a.z = a.z / length;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: u32 = 0xFF3366CC;
+    const DESTINATION: u32 = 0xFFC08040;
+
+    fn get_premultiplied_source(alpha: f32) -> [f32; 4] {
+        let [red, green, blue, _] = color_to_linear(SOURCE);
+        color_premultiply_alpha([red, green, blue, alpha])
+    }
+
+    #[test]
+    fn premultiplied_alpha_at_zero_keeps_the_destination() {
+        let blended = blend_colors(
+            get_premultiplied_source(0.0),
+            DESTINATION,
+            BlendMode::PremultipliedAlpha,
+        );
+        assert_eq!(blended, DESTINATION);
+    }
+
+    #[test]
+    fn premultiplied_alpha_at_one_gives_the_source() {
+        let blended = blend_colors(
+            get_premultiplied_source(1.0),
+            DESTINATION,
+            BlendMode::PremultipliedAlpha,
+        );
+        assert_eq!(blended, SOURCE);
+    }
+
+    #[test]
+    fn every_channel_value_round_trips_through_linear() {
+        for value in 0..=255u32 {
+            let color = (value << 24) | (value << 16) | (value << 8) | value;
+            assert_eq!(linear_to_color(color_to_linear(color)), color);
+        }
+    }
+
+    #[test]
+    fn color_to_linear_decodes_srgb_and_keeps_alpha() {
+        let [red, green, blue, alpha] = color_to_linear(0x80FF8000);
+        assert_eq!((red, blue), (1.0, 0.0));
+        assert!((green - 0.21586).abs() < 1e-4);
+        assert_eq!(alpha, 128.0 / 255.0);
+    }
+
+    #[test]
+    fn linear_to_color_clamps_out_of_range_channels() {
+        assert_eq!(linear_to_color([2.0, -1.0, 1.0, 1.5]), 0xFFFF00FF);
+    }
+}
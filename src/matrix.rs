@@ -112,6 +112,16 @@ pub fn matrix4_mul_matrix4(matrix1: Matrix4, matrix2: Matrix4) -> Matrix4 {
     res
 }
 
+pub fn matrix4_transpose(matrix: Matrix4) -> Matrix4 {
+    let mut res = Matrix4::default();
+    for i in 0..4 {
+        for j in 0..4 {
+            res.m[i][j] = matrix.m[j][i];
+        }
+    }
+    res
+}
+
 pub fn get_fps_view_matrix(camera: &mut Camera) -> Matrix4 {
     let mut target = Vec3 {
         x: 0.0,
@@ -161,10 +171,8 @@ pub fn get_look_at_view_matrix(camera_pos: Vec3, target: Vec3, up_view: Vec3) ->
     view_matrix
 }
 
-pub fn get_projection_matrix(fov: f32, aspect_ratio: f32) -> Matrix4 {
+pub fn get_projection_matrix(fov: f32, aspect_ratio: f32, z_near: f32, z_far: f32) -> Matrix4 {
     let field_of_view_scaling = 1.0 / (fov as f32 / 2.0).tan();
-    let z_far = 100.0;
-    let z_near = 0.1;
     let z_normalizer_left = z_far / (z_far - z_near);
     let z_normalizer_right = -(z_far / (z_far - z_near) * z_near);
     let res = Matrix4 {
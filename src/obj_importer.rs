@@ -1,4 +1,13 @@
-use crate::types::{get_vec3_identity, Entity, Mesh, Texture, TextureUV, Triangle, Vec3};
+use crate::environment::get_cube_face_index;
+use crate::game_state::get_default_material;
+use crate::math::{
+    color_to_channels, color_to_linear, generate_planar_uvs, generate_vertex_normals,
+    generate_vertex_tangents, srgb_to_linear,
+};
+use crate::types::{
+    get_vec3_identity, Entity, Environment, Mesh, Texture, TextureAddressMode, TextureUV, Triangle,
+    Vec3, VertexAttribute, VertexSemantic,
+};
 use image::GenericImageView;
 use native_dialog::FileDialog;
 use std::fs::File;
@@ -10,7 +19,9 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
         mesh: Mesh {
             vertices: vec![],
             triangles: vec![],
+            attributes: vec![],
         },
+        material: get_default_material(),
         rotation: Vec3::default(),
         scale: get_vec3_identity(),
         translation: Vec3::default(),
@@ -26,6 +37,10 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
     };
 
     let mut texture_uvs: Vec<TextureUV> = vec![];
+    let mut normals: Vec<f32> = vec![];
+    let mut normal_indices_by_triangle: Vec<[u32; 3]> = vec![];
+    let mut vertex_colors: Vec<f32> = vec![];
+    let mut vertex_indices: Vec<[u32; 3]> = vec![];
     let reader = BufReader::new(file);
 
     for line_res in reader.lines() {
@@ -39,7 +54,7 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
 
         if line.starts_with("v ") {
             let parts: Vec<&str> = line.split(' ').collect();
-            if parts.len() == 4 {
+            if parts.len() == 4 || parts.len() == 7 {
                 let x = match parts[1].parse::<f32>() {
                     Ok(val) => val,
                     Err(_) => continue,
@@ -53,6 +68,20 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
                     Err(_) => continue,
                 };
                 entity.mesh.vertices.push(Vec3 { x, y, z });
+                // Vertex colors are a common extension that appends r g b to the position
+                if parts.len() == 7 {
+                    for part in &parts[4..7] {
+                        vertex_colors.push(srgb_to_linear(part.parse::<f32>().unwrap_or(1.0)));
+                    }
+                    vertex_colors.push(1.0);
+                }
+            }
+        } else if line.starts_with("vn ") {
+            let parts: Vec<&str> = line.split(' ').collect();
+            if parts.len() == 4 {
+                for part in &parts[1..4] {
+                    normals.push(part.parse::<f32>().unwrap_or(0.0));
+                }
             }
         } else if line.starts_with("vt ") {
             let parts: Vec<&str> = line.split(' ').collect();
@@ -98,6 +127,17 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
                     Err(_) => continue,
                 };
 
+                let normal_indices = [&parts[1], &parts[2], &parts[3]].map(|part| {
+                    part.split('/')
+                        .nth(2)
+                        .and_then(|index| index.parse::<u32>().ok())
+                        .and_then(|index| index.checked_sub(1))
+                });
+                if let [Some(a_normal), Some(b_normal), Some(c_normal)] = normal_indices {
+                    normal_indices_by_triangle.push([a_normal, b_normal, c_normal]);
+                }
+                vertex_indices.push([a as u32 - 1, b as u32 - 1, c as u32 - 1]);
+
                 let triangle = Triangle {
                     a: a,
                     b: b,
@@ -111,6 +151,42 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
         }
     }
 
+    // Streams only count when every triangle has them and every index is in range, a partial
+    // stream can't be indexed
+    let triangle_count = entity.mesh.triangles.len();
+    let normal_count = (normals.len() / 3) as u32;
+    let are_normal_indices_valid = normal_indices_by_triangle
+        .iter()
+        .flatten()
+        .all(|&index| index < normal_count);
+    if !normals.is_empty()
+        && normal_indices_by_triangle.len() == triangle_count
+        && are_normal_indices_valid
+    {
+        entity.mesh.attributes.push(VertexAttribute {
+            semantic: VertexSemantic::Normal,
+            components: 3,
+            values: normals,
+            indices: normal_indices_by_triangle,
+        });
+    } else {
+        let normals = generate_vertex_normals(&entity.mesh);
+        entity.mesh.attributes.push(normals);
+    }
+    let tangents = generate_vertex_tangents(&entity.mesh);
+    entity.mesh.attributes.push(tangents);
+    // OBJ carries a single uv set, the second one is generated
+    let planar_uvs = generate_planar_uvs(&entity.mesh, 1);
+    entity.mesh.attributes.push(planar_uvs);
+    if vertex_colors.len() == entity.mesh.vertices.len() * 4 {
+        entity.mesh.attributes.push(VertexAttribute {
+            semantic: VertexSemantic::Color,
+            components: 4,
+            values: vertex_colors,
+            indices: vertex_indices,
+        });
+    }
+
     // entity.rotation.x = 18.422432;
     // entity.rotation.y = 14.808098;
     // entity.rotation.z = 0.13040066;
@@ -118,7 +194,8 @@ pub fn import_entity_from_obj(file_path: &str) -> Entity {
     entity
 }
 
-pub fn import_texture(path: &str) -> Texture {
+// Color images are stored in sRGB and get decoded, data like normal maps is read as it is
+fn import_image(path: &str, is_srgb: bool) -> Texture {
     let image = image::open(&Path::new(path)).unwrap();
     let (width, height) = image.dimensions();
 
@@ -132,16 +209,31 @@ pub fn import_texture(path: &str) -> Texture {
         let a = pixel[3] as u32;
 
         let argb = (a << 24) | (r << 16) | (g << 8) | b;
-        data.push(argb);
+        if is_srgb {
+            data.push(color_to_linear(argb));
+        } else {
+            data.push(color_to_channels(argb));
+        }
     }
     dbg!(data.len());
     Texture {
         data: data,
         width: width,
         height: height,
+        address_mode_u: TextureAddressMode::Repeat,
+        address_mode_v: TextureAddressMode::Repeat,
+        border_color: 0xFF000000,
     }
 }
 
+pub fn import_texture(path: &str) -> Texture {
+    import_image(path, true)
+}
+
+pub fn import_normal_map(path: &str) -> Texture {
+    import_image(path, false)
+}
+
 pub fn open_model_path() -> Option<PathBuf> {
     FileDialog::new()
         // .set_location("~")
@@ -150,6 +242,56 @@ pub fn open_model_path() -> Option<PathBuf> {
         .unwrap_or(None)
 }
 
+pub fn open_normal_map_path() -> Option<PathBuf> {
+    FileDialog::new()
+        .set_title("Normal map")
+        .add_filter("Texture files", &["png"])
+        .show_open_single_file()
+        .unwrap_or(None)
+}
+
+// One panorama, or the six faces of a cube map
+pub fn open_environment_paths() -> Vec<PathBuf> {
+    FileDialog::new()
+        .set_title("Environment")
+        .add_filter("Texture files", &["png", "jpg"])
+        .show_open_multiple_file()
+        .unwrap_or_default()
+}
+
+// Cube faces are ordered by their file names, or alphabetically when the names do not tell
+pub fn import_environment(paths: &[PathBuf]) -> Option<Environment> {
+    match paths.len() {
+        1 => {
+            let mut texture = import_texture(paths[0].to_str().unwrap_or(""));
+            texture.address_mode_v = TextureAddressMode::ClampToEdge;
+            Some(Environment::Panorama(texture))
+        }
+        6 => {
+            let mut ordered: Vec<&PathBuf> = paths.iter().collect();
+            let mut named: Vec<usize> = ordered
+                .iter()
+                .filter_map(|path| get_cube_face_index(path))
+                .collect();
+            named.sort();
+            named.dedup();
+            if named.len() == 6 {
+                ordered.sort_by_key(|path| get_cube_face_index(path));
+            } else {
+                ordered.sort();
+            }
+            let faces = std::array::from_fn(|face| {
+                let mut texture = import_texture(ordered[face].to_str().unwrap_or(""));
+                texture.address_mode_u = TextureAddressMode::ClampToEdge;
+                texture.address_mode_v = TextureAddressMode::ClampToEdge;
+                texture
+            });
+            Some(Environment::CubeMap(faces))
+        }
+        _ => None,
+    }
+}
+
 pub fn open_texture_path() -> Option<PathBuf> {
     FileDialog::new()
         // .set_location("~")
@@ -1,26 +1,73 @@
-use crate::types::{Camera, Entity, IntVec2, Plane, Texture, TextureUV, Vec2, Vec3};
-use crate::vector::{vector4_trunk, Vec4};
+use crate::anti_aliasing::{apply_fxaa, prepare_render_target, resolve_render_target};
+use crate::depth::{is_depth_test_passed, reciprocal_w_to_depth};
+use crate::types::{
+    BlendMode, Camera, Entity, FrameStats, IntVec2, Material, Plane, RenderSettings, ScreenRect,
+    TargetView, Vec3,
+};
+use crate::vector::{vector4_snap, vector4_trunk, Vec4};
 use std::cmp;
+use std::sync::atomic::Ordering;
 use std::thread;
 
+use crate::environment::{get_view_rays, render_skybox};
+use crate::fog::{apply_fog, get_frame_fog, Fog};
 use crate::game_state::{get_color_buffer, get_game_memory, HEIGHT, WIDTH};
 use crate::math::{
-    barycentric_weights, get_inv_slope, light_apply_intensity, perspective_project_point,
-    transform_vertex, triangle_avg, triangle_midpoint_uv, triangle_vec2_midpoint,
-    triangle_vec4_midpoint, vector2_add, vector2_mul_float, vector2_sub, vector3_add,
-    vector3_cross, vector3_dot, vector3_mul, vector3_mul_float, vector3_normalize, vector3_sub,
+    barycentric_weights, blend_colors, color_apply_opacity, color_to_linear, get_inv_slope,
+    get_model_view_matrix, is_alpha_discarded, perspective_project_point, transform_vertex,
+    triangle_avg, triangle_vec2_midpoint, triangle_vec4_midpoint, vector3_add, vector3_cross,
+    vector3_dot, vector3_mul, vector3_mul_float, vector3_normalize, vector3_sub,
 };
 use crate::matrix::{get_fps_view_matrix, get_projection_matrix, Matrix4};
 
+use crate::lighting::get_view_lights;
+use crate::shader::{
+    get_perspective_varyings, get_vertex_input, interpolate_varyings, FragmentShader,
+    PerspectiveVaryings, Shader, Varyings,
+};
+use crate::shading::get_entity_shader;
+use crate::shadow::render_shadow_maps;
 use crate::subpixel_rendering::subpixel_render_triangle;
+use crate::tile_rendering::render_tiles;
+
+const MIN_ITEMS_PER_THREAD: usize = 256;
+pub const MAX_CLIP_PLANES: usize = 6;
+// Each frustum plane adds at most one vertex to the clipped triangle
+pub const MAX_CLIP_POLYGON_VERTICES: usize = 3 + MAX_CLIP_PLANES;
+
+pub struct ProjectedTriangle {
+    pub points: [Vec4; 3],
+    // Shaded once here, every tile the triangle covers reuses them
+    pub varyings: [Varyings; 3],
+    pub normal: Vec3,
+    pub normal_avg: Vec3,
+    pub depth: f32,
+    pub entity_index: usize,
+}
 
-pub fn render_entity(entity: &Entity, camera: &mut Camera) {
-    let line_color = 0xFF00FF00;
+pub struct ShadedTriangle<'a> {
+    pub points: [Vec4; 3],
+    pub varyings: PerspectiveVaryings,
+    pub shader: &'a dyn FragmentShader,
+    pub material: &'a Material,
+    pub fog: Option<&'a Fog<'a>>,
+}
+
+// Clipped vertices are blends of the source corners, so weights stand in for every attribute
+pub struct ClipPolygon {
+    pub points: [Vec3; MAX_CLIP_POLYGON_VERTICES],
+    pub corner_weights: [Vec3; MAX_CLIP_POLYGON_VERTICES],
+    pub len: usize,
+}
+
+pub fn render_scene(camera: &mut Camera) {
     let game_memory = get_game_memory();
 
     let projection_matrix = get_projection_matrix(
         game_memory.view_settings.fov.y,
         HEIGHT as f32 / WIDTH as f32,
+        game_memory.view_settings.z_near,
+        game_memory.view_settings.z_far,
     );
     /*  Look-At method of view with locking on the target
     let view_matrix = get_look_at_view_matrix(
@@ -36,264 +83,432 @@ pub fn render_entity(entity: &Entity, camera: &mut Camera) {
             z: 0.0,
         },
     ); */
-    let view_matrix = get_fps_view_matrix(&mut game_memory.camera);
-    for (i, triangle) in entity.mesh.triangles.iter().enumerate() {
-        let x_index = (triangle.a as usize) - 1;
-        let y_index = (triangle.b as usize) - 1;
-        let z_index = (triangle.c as usize) - 1;
-        let p0 = transform_vertex(
-            entity.mesh.vertices[x_index],
-            entity.rotation,
-            entity.scale,
-            entity.translation,
-            view_matrix,
-        );
-        let p1 = transform_vertex(
-            entity.mesh.vertices[y_index],
-            entity.rotation,
-            entity.scale,
-            entity.translation,
-            view_matrix,
+    let view_matrix = get_fps_view_matrix(camera);
+    if game_memory.render_settings.use_skybox {
+        let view_rays = get_view_rays(view_matrix, projection_matrix);
+        render_skybox(
+            &mut game_memory.target,
+            &game_memory.environment,
+            &view_rays,
         );
-        let p2 = transform_vertex(
-            entity.mesh.vertices[z_index],
-            entity.rotation,
-            entity.scale,
-            entity.translation,
+    }
+
+    // Hidden-line wireframe still needs the surfaces in the depth buffer to hide back edges
+    game_memory.target.color_write = game_memory.render_settings.fill_triangles;
+
+    let fog = get_frame_fog(
+        &game_memory.view_settings,
+        &game_memory.environment,
+        view_matrix,
+        projection_matrix,
+    );
+    let mut view_lights = get_view_lights(&game_memory.lights, view_matrix);
+    let shadow_maps = render_shadow_maps(&mut view_lights, view_matrix);
+    let shaders: Vec<Box<dyn Shader>> = game_memory
+        .entities
+        .iter()
+        .map(|entity| {
+            get_entity_shader(
+                entity,
+                &game_memory.render_settings,
+                &view_lights,
+                &shadow_maps,
+                game_memory.ambient_light,
+                &game_memory.environment,
+                view_matrix,
+            )
+        })
+        .collect();
+
+    let mut projected_triangles: Vec<ProjectedTriangle> = vec![];
+    let mut transparent_triangles: Vec<ProjectedTriangle> = vec![];
+    for (entity_index, entity) in game_memory.entities.iter().enumerate() {
+        let entity_triangles = project_entity(
+            entity,
+            entity_index,
             view_matrix,
+            projection_matrix,
+            &game_memory.view_settings.planes,
+            shaders[entity_index].as_ref(),
         );
+        if entity.material.blend_mode == BlendMode::Opaque {
+            projected_triangles.extend(entity_triangles);
+        } else {
+            transparent_triangles.extend(entity_triangles);
+        }
+    }
 
-        let vector_ab = vector3_sub(p1, p0);
-        let vector_ac = vector3_sub(p2, p0);
-        let mut normal = vector3_cross(vector_ab, vector_ac);
-        vector3_normalize(&mut normal);
+    // Transparent surfaces blend over what is already drawn, so they go last and far to near
+    transparent_triangles.sort_by(|a, b| b.depth.total_cmp(&a.depth));
+    projected_triangles.extend(transparent_triangles);
+
+    let entities = &game_memory.entities;
+    let render_settings = &game_memory.render_settings;
+    let frame_stats = &game_memory.frame_stats;
+    render_tiles(
+        &mut game_memory.target,
+        &projected_triangles,
+        |target, projected_triangle, clip| {
+            let entity_index = projected_triangle.entity_index;
+            render_projected_triangle(
+                target,
+                projected_triangle,
+                &entities[entity_index],
+                shaders[entity_index].as_ref(),
+                fog.as_ref(),
+                clip,
+                render_settings,
+                frame_stats,
+            );
+        },
+    );
 
-        let camera_ray = vector3_sub(Vec3::default(), p0);
-        let dot = vector3_dot(normal, camera_ray);
+    game_memory.target.color_write = true;
+    for projected_triangle in &projected_triangles {
+        render_triangle_overlays(projected_triangle, projection_matrix);
+    }
+}
 
-        if dot < 0.0 {
-            continue;
+pub fn project_entity(
+    entity: &Entity,
+    entity_index: usize,
+    view_matrix: Matrix4,
+    projection_matrix: Matrix4,
+    planes: &[Plane],
+    shader: &dyn Shader,
+) -> Vec<ProjectedTriangle> {
+    let model_view_matrix = get_model_view_matrix(
+        entity.rotation,
+        entity.scale,
+        entity.translation,
+        view_matrix,
+    );
+    // Vertices are shared between triangles, so each one is transformed once up front
+    let view_vertices = map_chunks_parallel(&entity.mesh.vertices, |_, chunk, view_vertices| {
+        for vertex in chunk {
+            view_vertices.push(transform_vertex(*vertex, model_view_matrix));
         }
-        let normal_avg = triangle_avg(p0, p1, p2);
-
-        let (clipped_triangles, clipped_triangle_uvs) = clip_triangle(
-            &game_memory.view_settings.planes,
-            p0,
-            p1,
-            p2,
-            triangle.a_uv,
-            triangle.b_uv,
-            triangle.c_uv,
-        );
-        for j in 0..clipped_triangles.len() {
-            let clipped_triangle = &clipped_triangles[j];
-
-            let clipped_triangle_uv = &clipped_triangle_uvs[j];
-            let projected0 =
-                perspective_project_point(clipped_triangle[0], projection_matrix, HEIGHT, WIDTH);
-            let projected1 =
-                perspective_project_point(clipped_triangle[1], projection_matrix, HEIGHT, WIDTH);
-            let projected2 =
-                perspective_project_point(clipped_triangle[2], projection_matrix, HEIGHT, WIDTH);
-
-            if game_memory.render_settings.fill_triangles {
-                if game_memory.render_settings.use_textures {
-                    let texture_uv0 = clipped_triangle_uv[0].clone();
-                    let texture_uv1 = clipped_triangle_uv[1].clone();
-                    let texture_uv2 = clipped_triangle_uv[2].clone();
-                    if game_memory.render_settings.use_lighting {
-                        let light_dot = vector3_dot(game_memory.light, normal) * -1.0;
-                        fill_triangle_with_texture(
-                            vector4_trunk(projected0),
-                            vector4_trunk(projected1),
-                            vector4_trunk(projected2),
-                            texture_uv0,
-                            texture_uv1,
-                            texture_uv2,
-                            &game_memory.texture,
-                        );
-                    } else {
-                        fill_triangle_with_texture(
-                            vector4_trunk(projected0),
-                            vector4_trunk(projected1),
-                            vector4_trunk(projected2),
-                            texture_uv0,
-                            texture_uv1,
-                            texture_uv2,
-                            &game_memory.texture,
-                        );
-                    }
-                } else {
-                    let light_dot = vector3_dot(game_memory.light, normal) * -1.0;
-                    let mut color_by_light = game_memory.render_settings.default_render_color;
-                    if game_memory.render_settings.use_lighting {
-                        color_by_light = light_apply_intensity(color_by_light, light_dot);
-                    }
-                    subpixel_render_triangle(
-                        projected0.into(),
-                        projected1.into(),
-                        projected2.into(),
-                        &clipped_triangle_uv[0],
-                        &clipped_triangle_uv[1],
-                        &clipped_triangle_uv[2],
-                        &game_memory.texture,
-                        color_by_light,
-                        light_dot,
-                    );
-                    // fill_triangle(
-                    //     projected0.into(),
-                    //     projected1.into(),
-                    //     projected2.into(),
-                    //     color_by_light,
-                    // );
-                }
-            }
+    });
 
-            if game_memory.render_settings.draw_edges {
-                render_edges(
-                    projected0.into(),
-                    projected1.into(),
-                    projected2.into(),
-                    line_color,
+    map_chunks_parallel(
+        &entity.mesh.triangles,
+        |first_index, chunk, projected_triangles| {
+            for chunk_index in 0..chunk.len() {
+                project_triangle(
+                    entity,
+                    entity_index,
+                    first_index + chunk_index,
+                    &view_vertices,
+                    projection_matrix,
+                    planes,
+                    shader,
+                    projected_triangles,
                 );
             }
+        },
+    )
+}
 
-            if game_memory.render_settings.draw_vert {
-                render_verticies(projected0.into(), projected1.into(), projected2.into());
-            }
+pub fn map_chunks_parallel<T: Sync, R: Send>(
+    items: &[T],
+    map_chunk: impl Fn(usize, &[T], &mut Vec<R>) + Sync,
+) -> Vec<R> {
+    let num_threads = thread::available_parallelism()
+        .map(|p| p.get())
+        .unwrap_or(4)
+        .min((items.len() + MIN_ITEMS_PER_THREAD - 1) / MIN_ITEMS_PER_THREAD)
+        .max(1);
+    let items_per_thread = ((items.len() + num_threads - 1) / num_threads).max(1);
 
-            if game_memory.render_settings.show_normals {
-                render_normals(normal, normal_avg, projection_matrix);
-            }
+    let mut results: Vec<R> = Vec::with_capacity(items.len());
+    thread::scope(|s| {
+        let map_chunk = &map_chunk;
+        let handles: Vec<_> = items
+            .chunks(items_per_thread)
+            .enumerate()
+            .map(|(chunk_index, chunk)| {
+                s.spawn(move || {
+                    let mut chunk_results: Vec<R> = Vec::with_capacity(chunk.len());
+                    map_chunk(chunk_index * items_per_thread, chunk, &mut chunk_results);
+                    chunk_results
+                })
+            })
+            .collect();
+        // Joining in chunk order keeps the output the same as a sequential pass
+        for handle in handles {
+            results.extend(handle.join().unwrap());
+        }
+    });
+    results
+}
+
+pub fn project_triangle(
+    entity: &Entity,
+    entity_index: usize,
+    triangle_index: usize,
+    view_vertices: &[Vec3],
+    projection_matrix: Matrix4,
+    planes: &[Plane],
+    shader: &dyn Shader,
+    projected_triangles: &mut Vec<ProjectedTriangle>,
+) {
+    let game_memory = get_game_memory();
+    let target_width = game_memory.target.width;
+    let target_height = game_memory.target.height;
+    let triangle = &entity.mesh.triangles[triangle_index];
+
+    let p0 = view_vertices[(triangle.a as usize) - 1];
+    let p1 = view_vertices[(triangle.b as usize) - 1];
+    let p2 = view_vertices[(triangle.c as usize) - 1];
+
+    let vector_ab = vector3_sub(p1, p0);
+    let vector_ac = vector3_sub(p2, p0);
+    let mut normal = vector3_cross(vector_ab, vector_ac);
+    vector3_normalize(&mut normal);
+
+    let camera_ray = vector3_sub(Vec3::default(), p0);
+    let dot = vector3_dot(normal, camera_ray);
+
+    if dot < 0.0 {
+        return;
+    }
+    let normal_avg = triangle_avg(p0, p1, p2);
+
+    let polygon = clip_triangle(planes, p0, p1, p2);
+    for i in 2..polygon.len {
+        let clipped_triangle = [polygon.points[0], polygon.points[i - 1], polygon.points[i]];
+        let depth = triangle_avg(
+            clipped_triangle[0],
+            clipped_triangle[1],
+            clipped_triangle[2],
+        )
+        .z;
+        let project = |point: Vec3| {
+            perspective_project_point(point, projection_matrix, target_height, target_width)
+        };
+
+        let corner_weights = [
+            polygon.corner_weights[0],
+            polygon.corner_weights[i - 1],
+            polygon.corner_weights[i],
+        ];
+
+        projected_triangles.push(ProjectedTriangle {
+            points: [
+                project(clipped_triangle[0]),
+                project(clipped_triangle[1]),
+                project(clipped_triangle[2]),
+            ],
+            varyings: corner_weights.map(|corner_weights| {
+                shader.shade_vertex(&get_vertex_input(
+                    &entity.mesh,
+                    triangle_index,
+                    corner_weights,
+                    normal,
+                ))
+            }),
+            normal,
+            normal_avg,
+            depth,
+            entity_index,
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn render_projected_triangle(
+    target: &mut TargetView,
+    projected_triangle: &ProjectedTriangle,
+    entity: &Entity,
+    shader: &dyn Shader,
+    fog: Option<&Fog>,
+    clip: &ScreenRect,
+    render_settings: &RenderSettings,
+    frame_stats: &FrameStats,
+) {
+    let [projected0, projected1, projected2] = projected_triangle.points;
+
+    if render_settings.fill_triangles || render_settings.hidden_line {
+        if render_settings.use_scanline_rasterizer {
+            fill_triangle_shaded(
+                target,
+                [
+                    vector4_trunk(projected0),
+                    vector4_trunk(projected1),
+                    vector4_trunk(projected2),
+                ],
+                &projected_triangle.varyings,
+                shader,
+                &entity.material,
+                fog,
+                clip,
+            );
+        } else {
+            let subpixel_bits = render_settings.subpixel_bits;
+            subpixel_render_triangle(
+                target,
+                [
+                    vector4_snap(projected0, subpixel_bits),
+                    vector4_snap(projected1, subpixel_bits),
+                    vector4_snap(projected2, subpixel_bits),
+                ],
+                &projected_triangle.varyings,
+                shader,
+                &entity.material,
+                fog,
+                clip,
+                render_settings,
+                frame_stats,
+            );
+        }
+    }
+}
+
+pub fn render_triangle_overlays(
+    projected_triangle: &ProjectedTriangle,
+    projection_matrix: Matrix4,
+) {
+    let line_color = 0xFF00FF00;
+    let game_memory = get_game_memory();
+    let [projected0, projected1, projected2] = projected_triangle.points;
+
+    if game_memory.render_settings.draw_edges {
+        render_edges(projected0, projected1, projected2, line_color);
+    }
+
+    if game_memory.render_settings.draw_vert {
+        render_verticies(projected0, projected1, projected2);
+    }
+
+    if game_memory.render_settings.show_normals {
+        render_normals(
+            projected_triangle.normal,
+            projected_triangle.normal_avg,
+            projection_matrix,
+        );
+    }
+}
+
+pub fn get_plane_distance(plane: &Plane, point: Vec3) -> f32 {
+    vector3_dot(vector3_sub(point, plane.position), plane.normal_dirrection)
+}
+
+pub fn get_outcode(planes: &[Plane], point: Vec3) -> u32 {
+    let mut outcode = 0;
+    for (plane_index, plane) in planes.iter().enumerate() {
+        if get_plane_distance(plane, point) <= 0.0 {
+            outcode |= 1 << plane_index;
         }
     }
+    outcode
 }
 
-pub fn clip_triangle(
-    planes: &Vec<Plane>,
-    point0: Vec3,
-    point1: Vec3,
-    point2: Vec3,
-    point0_uv: TextureUV,
-    point1_uv: TextureUV,
-    point2_uv: TextureUV,
-) -> (Vec<Vec<Vec3>>, Vec<Vec<TextureUV>>) {
-    let mut polygon_points: Vec<Vec3> = vec![];
-    let mut polygon_uvs: Vec<TextureUV> = vec![];
-    polygon_points.push(point0);
-    polygon_points.push(point1);
-    polygon_points.push(point2);
-
-    polygon_uvs.push(point0_uv);
-    polygon_uvs.push(point1_uv);
-    polygon_uvs.push(point2_uv);
-
-    for plane in planes {
-        (polygon_points, polygon_uvs) = clip_polygon(&plane, &polygon_points, &polygon_uvs);
-    }
-    let mut triangles: Vec<Vec<Vec3>> = vec![];
-    let mut trinagles_uv: Vec<Vec<TextureUV>> = vec![];
-    if polygon_points.len() < 3 {
-        return (triangles, trinagles_uv);
-    }
-    let mut i = 2;
-    loop {
-        let mut triangle: Vec<Vec3> = vec![];
-        triangle.push(polygon_points[0]);
-        triangle.push(polygon_points[i - 1]);
-        triangle.push(polygon_points[i]);
-        triangles.push(triangle);
-
-        let mut triangle_uv: Vec<TextureUV> = vec![];
-        triangle_uv.push(polygon_uvs[0]);
-        triangle_uv.push(polygon_uvs[i - 1]);
-        triangle_uv.push(polygon_uvs[i]);
-
-        trinagles_uv.push(triangle_uv);
-
-        i += 1;
-        if i >= polygon_points.len() {
-            break;
+pub fn clip_triangle(planes: &[Plane], point0: Vec3, point1: Vec3, point2: Vec3) -> ClipPolygon {
+    debug_assert!(planes.len() <= MAX_CLIP_PLANES);
+    let mut polygon = ClipPolygon {
+        points: [point0; MAX_CLIP_POLYGON_VERTICES],
+        corner_weights: [Vec3::default(); MAX_CLIP_POLYGON_VERTICES],
+        len: 0,
+    };
+
+    let outcode0 = get_outcode(planes, point0);
+    let outcode1 = get_outcode(planes, point1);
+    let outcode2 = get_outcode(planes, point2);
+    // Every vertex is behind the same plane, nothing of the triangle is visible
+    if outcode0 & outcode1 & outcode2 != 0 {
+        return polygon;
+    }
+
+    polygon.points[1] = point1;
+    polygon.points[2] = point2;
+    polygon.corner_weights[0] = Vec3 {
+        x: 1.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    polygon.corner_weights[1] = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    polygon.corner_weights[2] = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+    polygon.len = 3;
+
+    // Only planes with a vertex behind them can cut the triangle
+    let crossed_planes = outcode0 | outcode1 | outcode2;
+    for (plane_index, plane) in planes.iter().enumerate() {
+        if crossed_planes & (1 << plane_index) != 0 {
+            polygon = clip_polygon(plane, &polygon);
         }
     }
-    (triangles, trinagles_uv)
-}
-
-pub fn clip_polygon(
-    plane: &Plane,
-    polygon_points: &Vec<Vec3>,
-    polygon_uvs: &Vec<TextureUV>,
-) -> (Vec<Vec3>, Vec<TextureUV>) {
-    let mut inside_points: Vec<Vec3> = vec![];
-
-    let mut inside_uvs: Vec<TextureUV> = vec![];
-    if polygon_points.len() == 0 {
-        return (inside_points, inside_uvs);
-    }
-    let mut prev_point = polygon_points.last().unwrap().clone();
-    let mut prev_uv = polygon_uvs.last().unwrap().clone();
-    let prev_point_vec = vector3_sub(prev_point, plane.position);
-    let mut previous_dot = vector3_dot(prev_point_vec, plane.normal_dirrection);
-    for i in 0..polygon_points.len() {
-        let point = polygon_points[i];
-        let uv = polygon_uvs[i];
-        let sp = vector3_sub(point, plane.position);
-        let dot = vector3_dot(sp, plane.normal_dirrection);
+    polygon
+}
+
+pub fn clip_polygon(plane: &Plane, polygon: &ClipPolygon) -> ClipPolygon {
+    let mut inside = ClipPolygon {
+        points: polygon.points,
+        corner_weights: polygon.corner_weights,
+        len: 0,
+    };
+    if polygon.len == 0 {
+        return inside;
+    }
+    let mut prev_point = polygon.points[polygon.len - 1];
+    let mut prev_weights = polygon.corner_weights[polygon.len - 1];
+    let mut previous_dot = get_plane_distance(plane, prev_point);
+    for i in 0..polygon.len {
+        let point = polygon.points[i];
+        let weights = polygon.corner_weights[i];
+        let dot = get_plane_distance(plane, point);
         if dot * previous_dot < 0.0 {
             let t = (previous_dot) / (previous_dot - dot);
-            let intersect_point = vector3_add(
+            inside.points[inside.len] = vector3_add(
                 prev_point,
                 vector3_mul_float(vector3_sub(point, prev_point), t),
             );
-            let intersect_uv = vector2_add(
-                prev_uv.into(),
-                vector2_mul_float(vector2_sub(uv.into(), prev_uv.into()), t),
-            )
-            .into();
-            // dbg!("new clip point");
-            inside_points.push(intersect_point);
-            inside_uvs.push(intersect_uv);
+            inside.corner_weights[inside.len] = vector3_add(
+                prev_weights,
+                vector3_mul_float(vector3_sub(weights, prev_weights), t),
+            );
+            inside.len += 1;
         }
 
         if dot > 0.0 {
-            inside_points.push(point);
-            inside_uvs.push(uv);
+            inside.points[inside.len] = point;
+            inside.corner_weights[inside.len] = weights;
+            inside.len += 1;
         }
-        prev_point = point.clone();
-        prev_uv = uv.clone();
-        previous_dot = dot.clone();
+        prev_point = point;
+        prev_weights = weights;
+        previous_dot = dot;
     }
-    (inside_points, inside_uvs)
+    inside
 }
 
-pub fn render_verticies(p0: Vec2, p1: Vec2, p2: Vec2) {
-    render_box(p0.x as i32, p0.y as i32, 4, 4, 0xFFFF0000);
-    render_box(p1.x as i32, p1.y as i32, 4, 4, 0xFFFF0000);
-    render_box(p2.x as i32, p2.y as i32, 4, 4, 0xFFFF0000);
+pub fn render_verticies(p0: Vec4, p1: Vec4, p2: Vec4) {
+    let target = &get_game_memory().target;
+    let box_width = 4 * target.scale_x;
+    let box_height = 4 * target.scale_y;
+    for p in [p0, p1, p2] {
+        render_box(
+            p.x as i32,
+            p.y as i32,
+            box_width,
+            box_height,
+            1.0 / p.w,
+            0xFFFF0000,
+        );
+    }
 }
 
-pub fn render_edges(p0: Vec2, p1: Vec2, p2: Vec2, line_color: u32) {
-    render_line(
-        p0.x as i32,
-        p0.y as i32,
-        p1.x as i32,
-        p1.y as i32,
-        line_color,
-    );
-    render_line(
-        p1.x as i32,
-        p1.y as i32,
-        p2.x as i32,
-        p2.y as i32,
-        line_color,
-    );
-    render_line(
-        p0.x as i32,
-        p0.y as i32,
-        p2.x as i32,
-        p2.y as i32,
-        line_color,
-    );
+pub fn render_edges(p0: Vec4, p1: Vec4, p2: Vec4, line_color: u32) {
+    render_overlay_line(p0, p1, line_color);
+    render_overlay_line(p1, p2, line_color);
+    render_overlay_line(p0, p2, line_color);
 }
 
 pub fn render_normals(normal: Vec3, normal_avg: Vec3, projection_matrix: Matrix4) {
@@ -308,18 +523,108 @@ pub fn render_normals(normal: Vec3, normal_avg: Vec3, projection_matrix: Matrix4
             },
         ),
     );
+    let target = &get_game_memory().target;
     let projected_normal_start =
-        perspective_project_point(normal_avg, projection_matrix, HEIGHT, WIDTH);
+        perspective_project_point(normal_avg, projection_matrix, target.height, target.width);
     let projected_normal_end =
-        perspective_project_point(normal_end, projection_matrix, HEIGHT, WIDTH);
-
-    render_line(
-        projected_normal_start.x as i32,
-        projected_normal_start.y as i32,
-        projected_normal_end.x as i32,
-        projected_normal_end.y as i32,
-        0xFFFFFF00,
-    );
+        perspective_project_point(normal_end, projection_matrix, target.height, target.width);
+
+    render_overlay_line(projected_normal_start, projected_normal_end, 0xFFFFFF00);
+}
+
+pub fn render_overlay_line(start: Vec4, end: Vec4, color: u32) {
+    let game_memory = get_game_memory();
+    let render_settings = &game_memory.render_settings;
+    if render_settings.antialias_lines || render_settings.line_width > 1.0 {
+        render_line_smooth(
+            start,
+            end,
+            render_settings.line_width * game_memory.target.scale_x as f32,
+            render_settings.antialias_lines,
+            color,
+        );
+    } else if render_settings.depth_test_overlays {
+        render_line_depth_tested(start, end, color);
+    } else {
+        render_line(
+            start.x as i32,
+            start.y as i32,
+            end.x as i32,
+            end.y as i32,
+            color,
+        );
+    }
+}
+
+pub fn render_line_smooth(start: Vec4, end: Vec4, width: f32, antialiased: bool, color: u32) {
+    let color = color_to_linear(color);
+    let radius = width / 2.0;
+    let reach = radius + 1.0;
+    let reciprocal_w_start = 1.0 / start.w;
+    let reciprocal_w_end = 1.0 / end.w;
+
+    // Walk the major axis and only test the few pixels across the line on the minor one
+    let is_x_major = (end.x - start.x).abs() >= (end.y - start.y).abs();
+    let (major_start, minor_start, major_end, minor_end) = if is_x_major {
+        (start.x, start.y, end.x, end.y)
+    } else {
+        (start.y, start.x, end.y, end.x)
+    };
+    let major_delta = major_end - major_start;
+    let minor_delta = minor_end - minor_start;
+    let length_squared = major_delta * major_delta + minor_delta * minor_delta;
+    let slope = if major_delta != 0.0 {
+        minor_delta / major_delta
+    } else {
+        0.0
+    };
+    let minor_reach = reach * (1.0 + slope * slope).sqrt();
+
+    let major_begin = (major_start.min(major_end) - reach).floor() as i32;
+    let major_finish = (major_start.max(major_end) + reach).ceil() as i32;
+    for major in major_begin..=major_finish {
+        let major_center = major as f32 + 0.5;
+        let mut t = 0.0;
+        if major_delta != 0.0 {
+            t = ((major_center - major_start) / major_delta).clamp(0.0, 1.0);
+        }
+        let minor_on_line = minor_start + t * minor_delta;
+        let minor_begin = (minor_on_line - minor_reach).floor() as i32;
+        let minor_finish = (minor_on_line + minor_reach).ceil() as i32;
+        for minor in minor_begin..=minor_finish {
+            let minor_center = minor as f32 + 0.5;
+            let mut segment_t = 0.0;
+            if length_squared > 0.0 {
+                segment_t = (((major_center - major_start) * major_delta
+                    + (minor_center - minor_start) * minor_delta)
+                    / length_squared)
+                    .clamp(0.0, 1.0);
+            }
+            let distance_major = major_center - (major_start + segment_t * major_delta);
+            let distance_minor = minor_center - (minor_start + segment_t * minor_delta);
+            let distance =
+                (distance_major * distance_major + distance_minor * distance_minor).sqrt();
+
+            let coverage = if antialiased {
+                (radius + 0.5 - distance).clamp(0.0, 1.0)
+            } else if distance <= radius {
+                1.0
+            } else {
+                0.0
+            };
+            if coverage <= 0.0 {
+                continue;
+            }
+            let reciprocal_w =
+                reciprocal_w_start + (reciprocal_w_end - reciprocal_w_start) * segment_t;
+            let color = color_apply_opacity(color, coverage);
+            if is_x_major {
+                render_overlay_pixel(major, minor, reciprocal_w, color);
+            } else {
+                render_overlay_pixel(minor, major, reciprocal_w, color);
+            }
+        }
+    }
 }
 
 pub fn render_line(x_start: i32, y_start: i32, x_end: i32, y_end: i32, color: u32) {
@@ -348,14 +653,53 @@ pub fn render_line(x_start: i32, y_start: i32, x_end: i32, y_end: i32, color: u3
     }
 }
 
-pub fn render_box(x_pos: i32, y_pos: i32, box_width: u32, box_height: u32, color: u32) {
+pub fn render_line_depth_tested(start: Vec4, end: Vec4, color: u32) {
+    let color = color_to_linear(color);
+    let x_start = start.x as i32;
+    let y_start = start.y as i32;
+    let dx = end.x as i32 - x_start;
+    let dy = end.y as i32 - y_start;
+
+    let side_length = cmp::max(dx.abs(), dy.abs());
+
+    let x_inc: f32 = dx as f32 / side_length as f32;
+    let y_inc: f32 = dy as f32 / side_length as f32;
+    let reciprocal_w_start = 1.0 / start.w;
+    let reciprocal_w_inc = (1.0 / end.w - reciprocal_w_start) / side_length as f32;
+
+    let mut x_cur = x_start as f32;
+    let mut y_cur = y_start as f32;
+    let mut reciprocal_w_cur = reciprocal_w_start;
+
+    for _i in 0..side_length + 1 {
+        render_overlay_pixel(
+            x_cur.trunc() as i32,
+            y_cur.trunc() as i32,
+            reciprocal_w_cur,
+            color,
+        );
+        x_cur += x_inc;
+        y_cur += y_inc;
+        reciprocal_w_cur += reciprocal_w_inc;
+    }
+}
+
+pub fn render_box(
+    x_pos: i32,
+    y_pos: i32,
+    box_width: u32,
+    box_height: u32,
+    reciprocal_w: f32,
+    color: u32,
+) {
+    let color = color_to_linear(color);
     let box_width_i32 = box_width as i32;
     let box_height_i32 = box_height as i32;
     let x_begin = cmp::max(0, x_pos);
     let y_begin = cmp::max(0, y_pos);
 
-    let width_i32 = WIDTH as i32;
-    let height_i32 = HEIGHT as i32;
+    let width_i32 = get_game_memory().target.width as i32;
+    let height_i32 = get_game_memory().target.height as i32;
 
     let x_end = cmp::min(width_i32, x_pos.saturating_add(box_width_i32));
     let y_end = cmp::min(height_i32, y_pos.saturating_add(box_height_i32));
@@ -364,7 +708,7 @@ pub fn render_box(x_pos: i32, y_pos: i32, box_width: u32, box_height: u32, color
             if x > width_i32 {
                 break;
             }
-            render_pixel(x, y, color);
+            render_overlay_pixel(x, y, reciprocal_w, color);
         }
     }
 }
@@ -453,104 +797,78 @@ pub fn fill_flat_top_triangle(p0: IntVec2, p1: IntVec2, p2: IntVec2, color: u32)
     }
 }
 
-pub fn fill_triangle_with_texture(
-    p0: Vec4,
-    p1: Vec4,
-    p2: Vec4,
-    texture_uv0: TextureUV,
-    texture_uv1: TextureUV,
-    texture_uv2: TextureUV,
-    texture: &Texture,
+pub fn fill_triangle_shaded(
+    target: &mut TargetView,
+    points: [Vec4; 3],
+    varyings: &[Varyings; 3],
+    shader: &dyn FragmentShader,
+    material: &Material,
+    fog: Option<&Fog>,
+    clip: &ScreenRect,
 ) {
-    let mut top_point = p0;
-    let mut top_point_texture = texture_uv0;
-
-    let mut mid_point = p1;
-    let mut mid_point_texture = texture_uv1;
-
-    let mut bottom_point = p2;
-    let mut bottom_point_texture = texture_uv2;
+    let triangle = ShadedTriangle {
+        points,
+        varyings: get_perspective_varyings(varyings, [points[0].w, points[1].w, points[2].w]),
+        shader,
+        material,
+        fog,
+    };
+    let [mut top_point, mut mid_point, mut bottom_point] = points;
 
     if top_point.y > mid_point.y {
         std::mem::swap(&mut top_point, &mut mid_point);
-        std::mem::swap(&mut top_point_texture, &mut mid_point_texture);
     }
     if mid_point.y > bottom_point.y {
         std::mem::swap(&mut mid_point, &mut bottom_point);
-        std::mem::swap(&mut mid_point_texture, &mut bottom_point_texture);
     }
     if top_point.y > mid_point.y {
         std::mem::swap(&mut top_point, &mut mid_point);
-        std::mem::swap(&mut top_point_texture, &mut mid_point_texture);
     }
 
     if mid_point.y == bottom_point.y {
-        fill_flat_bottom_triangle_with_texture(
+        fill_flat_bottom_triangle_shaded(
+            target,
             top_point,
             mid_point,
             bottom_point,
-            top_point_texture,
-            mid_point_texture,
-            bottom_point_texture,
-            texture,
+            &triangle,
+            clip,
         );
     } else if top_point.y == mid_point.y {
-        fill_flat_top_triangle_with_texture(
-            bottom_point,
-            mid_point,
-            top_point,
-            bottom_point_texture,
-            mid_point_texture,
-            top_point_texture,
-            texture,
-        );
+        fill_flat_top_triangle_shaded(target, bottom_point, mid_point, top_point, &triangle, clip);
     } else {
         let mid_intersect_point = triangle_vec4_midpoint(top_point, mid_point, bottom_point);
-        let mid_intersect_uv = triangle_midpoint_uv(
-            top_point,
-            mid_point,
-            bottom_point,
-            top_point_texture,
-            mid_point_texture,
-            bottom_point_texture,
-        );
-        fill_flat_bottom_triangle_with_texture(
+        fill_flat_bottom_triangle_shaded(
+            target,
             top_point,
             mid_point,
             mid_intersect_point,
-            top_point_texture,
-            mid_point_texture,
-            mid_intersect_uv,
-            texture,
+            &triangle,
+            clip,
         );
-        fill_flat_top_triangle_with_texture(
+        fill_flat_top_triangle_shaded(
+            target,
             bottom_point,
             mid_point,
             mid_intersect_point,
-            bottom_point_texture,
-            mid_point_texture,
-            mid_intersect_uv,
-            texture,
+            &triangle,
+            clip,
         );
     }
 }
 
-pub fn fill_flat_bottom_triangle_with_texture(
+pub fn fill_flat_bottom_triangle_shaded(
+    target: &mut TargetView,
     p0: Vec4,
     p1: Vec4,
     p2: Vec4,
-    texture_uv0: TextureUV,
-    texture_uv1: TextureUV,
-    texture_uv2: TextureUV,
-    texture: &Texture,
+    triangle: &ShadedTriangle,
+    clip: &ScreenRect,
 ) {
     let mut left_point = p1;
-    let mut left_point_uv = texture_uv1;
     let mut right_point = p2;
-    let mut right_point_uv = texture_uv2;
     if left_point.x > right_point.x {
         std::mem::swap(&mut left_point, &mut right_point);
-        std::mem::swap(&mut left_point_uv, &mut right_point_uv);
     }
 
     let max_width = right_point.x.trunc() as i32 - left_point.x.trunc() as i32;
@@ -563,22 +881,13 @@ pub fn fill_flat_bottom_triangle_with_texture(
 
     let y_start = p0.y.trunc() as i32;
     let y_end = p2.y.trunc() as i32 + 1;
-    // dbg!(p0.x, p0.y, p1.x, p1.y, p2.x, p2.y);
-    // dbg!(y_start, y_end, left_slope, right_slope, x_start, x_end);
     for y in y_start..y_end {
-        let xs = x_start as i32;
-        let xe = x_end as i32;
-        for x in xs..xe + 1 {
-            render_texel(
-                IntVec2 { x: x, y: y },
-                p0,
-                left_point,
-                right_point,
-                texture_uv0,
-                left_point_uv,
-                right_point_uv,
-                texture,
-            );
+        let xs = cmp::max(x_start as i32, clip.x_min);
+        let xe = cmp::min(x_end as i32, clip.x_max - 1);
+        if y >= clip.y_min && y < clip.y_max {
+            for x in xs..xe + 1 {
+                render_shaded_pixel(target, IntVec2 { x: x, y: y }, triangle);
+            }
         }
         x_start += left_slope;
         x_end += right_slope;
@@ -589,59 +898,46 @@ pub fn fill_flat_bottom_triangle_with_texture(
     }
 }
 
-pub fn render_texel(
-    p: IntVec2,
-    p0: Vec4,
-    p1: Vec4,
-    p2: Vec4,
-    uv0: TextureUV,
-    uv1: TextureUV,
-    uv2: TextureUV,
-    texture: &Texture,
-) {
+// Weights are taken against the whole triangle, so the scanline split adds no error
+pub fn render_shaded_pixel(target: &mut TargetView, p: IntVec2, triangle: &ShadedTriangle) {
+    let [p0, p1, p2] = triangle.points;
     let weights = barycentric_weights(p0.into(), p1.into(), p2.into(), p);
-    let alpha = weights.x;
-    let beta = weights.y;
-    let gamma = weights.z;
-
-    let interpolated_u = uv0.u / p0.w * alpha + uv1.u / p1.w * beta + uv2.u / p2.w * gamma;
-    let interpolated_v = uv0.v / p0.w * alpha + uv1.v / p1.w * beta + uv2.v / p2.w * gamma;
-    let interpolated_reciprocal_w =
-        (1.0 / p0.w) * alpha + (1.0 / p1.w) * beta + (1.0 / p2.w) * gamma;
+    let varyings = interpolate_varyings(&triangle.varyings, weights.x, weights.y, weights.z);
 
-    let texture_x_coord =
-        ((interpolated_u / interpolated_reciprocal_w) * texture.width as f32).abs() as u32;
-    let texture_y_coord =
-        ((interpolated_v / interpolated_reciprocal_w) * texture.height as f32).abs() as u32;
-
-    let index = (texture_y_coord * texture.width + texture_x_coord) as usize
-        % (texture.width * texture.height) as usize;
+    let mut color = triangle.shader.shade_fragment(&varyings);
+    if is_alpha_discarded(color, triangle.material.alpha_cutoff) {
+        return;
+    }
 
-    let w = 1.0 - interpolated_reciprocal_w;
-    let pixel_index = (p.y as u32 * WIDTH + p.x as u32) as usize;
-    if pixel_index < get_game_memory().z_buffer.len() && get_game_memory().z_buffer[pixel_index] > w
-    {
-        get_game_memory().z_buffer[pixel_index] = w;
-        render_pixel(p.x, p.y, texture.data[index]);
+    let [reciprocal_w0, reciprocal_w1, reciprocal_w2] = triangle.varyings.reciprocal_w;
+    let interpolated_reciprocal_w =
+        reciprocal_w0 * weights.x + reciprocal_w1 * weights.y + reciprocal_w2 * weights.z;
+    if let Some(fog) = triangle.fog {
+        color = apply_fog(
+            fog,
+            color,
+            p.x as f32 + 0.5,
+            p.y as f32 + 0.5,
+            1.0 / interpolated_reciprocal_w,
+            triangle.material.blend_mode,
+        );
     }
+    let depth = reciprocal_w_to_depth(&target.depth_state, interpolated_reciprocal_w as f64);
+    render_fragment(target, p.x, p.y, depth, color, triangle.material);
 }
 
-pub fn fill_flat_top_triangle_with_texture(
+pub fn fill_flat_top_triangle_shaded(
+    target: &mut TargetView,
     p0: Vec4,
     p1: Vec4,
     p2: Vec4,
-    texture_uv0: TextureUV,
-    texture_uv1: TextureUV,
-    texture_uv2: TextureUV,
-    texture: &Texture,
+    triangle: &ShadedTriangle,
+    clip: &ScreenRect,
 ) {
     let mut left_point = p1;
-    let mut left_point_uv = texture_uv1;
     let mut right_point = p2;
-    let mut right_point_uv = texture_uv2;
     if left_point.x > right_point.x {
         std::mem::swap(&mut left_point, &mut right_point);
-        std::mem::swap(&mut left_point_uv, &mut right_point_uv);
     }
 
     let max_width = right_point.x.trunc() as i32 - left_point.x.trunc() as i32;
@@ -656,19 +952,12 @@ pub fn fill_flat_top_triangle_with_texture(
     let y_end = right_point.y.trunc() as i32;
     let mut y = y_start;
     while y >= y_end {
-        let xs = x_start as i32;
-        let xe = x_end as i32;
-        for x in xs..xe + 1 {
-            render_texel(
-                IntVec2 { x: x, y: y },
-                p0,
-                left_point,
-                right_point,
-                texture_uv0,
-                left_point_uv,
-                right_point_uv,
-                texture,
-            );
+        let xs = cmp::max(x_start as i32, clip.x_min);
+        let xe = cmp::min(x_end as i32, clip.x_max - 1);
+        if y >= clip.y_min && y < clip.y_max {
+            for x in xs..xe + 1 {
+                render_shaded_pixel(target, IntVec2 { x: x, y: y }, triangle);
+            }
         }
         x_start -= left_slope;
         x_end -= right_slope;
@@ -681,14 +970,78 @@ pub fn fill_flat_top_triangle_with_texture(
 }
 
 pub fn render_pixel(x_pos: i32, y_pos: i32, color: u32) {
-    let color_buffer = get_color_buffer();
-    let width_i32 = WIDTH as i32;
-    let height_i32 = HEIGHT as i32;
+    let target = &mut get_game_memory().target;
+    let width_i32 = target.width as i32;
+    let height_i32 = target.height as i32;
     if x_pos < width_i32 && x_pos >= 0 && y_pos >= 0 && y_pos < height_i32 {
-        color_buffer[(y_pos * width_i32 + x_pos) as usize] = color;
+        let samples = target.samples as usize;
+        let pixel_index = (y_pos * width_i32 + x_pos) as usize * samples;
+        target.color[pixel_index..pixel_index + samples].fill(color);
     }
 }
 
+// Overlays take 1/w so the bias pulls them toward the camera in every depth format
+pub fn render_overlay_pixel(x_pos: i32, y_pos: i32, reciprocal_w: f32, color: [f32; 4]) {
+    let game_memory = get_game_memory();
+    let depth_test = game_memory.render_settings.depth_test_overlays;
+    let depth_bias = game_memory.render_settings.overlay_depth_bias;
+    let target = &mut game_memory.target;
+    let depth = reciprocal_w_to_depth(&target.depth_state, (reciprocal_w + depth_bias) as f64);
+    let width_i32 = target.width as i32;
+    let height_i32 = target.height as i32;
+    if x_pos < width_i32 && x_pos >= 0 && y_pos >= 0 && y_pos < height_i32 {
+        let samples = target.samples as usize;
+        let pixel_index = (y_pos * width_i32 + x_pos) as usize * samples;
+        for sample_index in pixel_index..pixel_index + samples {
+            if depth_test
+                && !is_depth_test_passed(
+                    target.depth_state.compare,
+                    depth,
+                    target.depth[sample_index],
+                )
+            {
+                continue;
+            }
+            target.color[sample_index] =
+                blend_colors(color, target.color[sample_index], BlendMode::Alpha);
+        }
+    }
+}
+
+// Returns whether the fragment wrote depth, so callers know when coarse depth is stale
+pub fn render_fragment(
+    target: &mut TargetView,
+    x_pos: i32,
+    y_pos: i32,
+    depth: f32,
+    color: [f32; 4],
+    material: &Material,
+) -> bool {
+    let width_i32 = target.width as i32;
+    let mut is_depth_written = false;
+    if x_pos < width_i32 && x_pos >= 0 && y_pos >= target.y_min && y_pos < target.y_max {
+        let samples = target.samples as usize;
+        let pixel_index = ((y_pos - target.y_min) * width_i32 + x_pos) as usize * samples;
+        for sample_index in pixel_index..pixel_index + samples {
+            if is_depth_test_passed(
+                target.depth_state.compare,
+                depth,
+                target.depth[sample_index],
+            ) {
+                if material.depth_write {
+                    target.depth[sample_index] = depth;
+                    is_depth_written = true;
+                }
+                if target.color_write {
+                    target.color[sample_index] =
+                        blend_colors(color, target.color[sample_index], material.blend_mode);
+                }
+            }
+        }
+    }
+    is_depth_written
+}
+
 #[allow(dead_code)]
 pub fn clear_color_buffer(color: u32) {
     let color_buffer = get_color_buffer();
@@ -717,34 +1070,44 @@ pub fn render_color_buffer(
 }
 
 pub fn make_grid(color_line: u32, color_back: u32, width: u32, height: u32) {
+    let target_height = get_game_memory().target.height as usize;
     let num_threads = thread::available_parallelism()
         .map(|p| p.get())
         .unwrap_or(4)
-        .min(HEIGHT as usize);
+        .min(target_height);
 
     let mid_x = (width / 2) as usize;
     let mid_y = (height / 2) as usize;
 
-    let rows_per_thread = (HEIGHT as usize + num_threads - 1) / num_threads;
+    let rows_per_thread = (target_height + num_threads - 1) / num_threads;
 
     thread::scope(|s| {
         for thread_id in 0..num_threads {
             let start_row = thread_id * rows_per_thread;
-            let end_row = std::cmp::min((thread_id + 1) * rows_per_thread, HEIGHT as usize);
+            let end_row = std::cmp::min((thread_id + 1) * rows_per_thread, target_height);
 
             s.spawn(move || {
-                let buffer = get_color_buffer();
-
-                for y in start_row..end_row {
-                    let row_start = y * WIDTH as usize;
+                let target = &mut get_game_memory().target;
+                let target_width = target.width as usize;
+                let scale_x = target.scale_x as usize;
+                let scale_y = target.scale_y as usize;
+                let samples = target.samples as usize;
+
+                for target_y in start_row..end_row {
+                    let row_start = target_y * target_width * samples;
+                    let y = target_y / scale_y;
                     let is_horizontal_line = y == mid_y;
 
-                    for x in 0..WIDTH as usize {
-                        if is_horizontal_line || x == mid_x || (x % 10 == 0 && y % 10 == 0) {
-                            buffer[row_start + x] = color_line;
-                        } else {
-                            buffer[row_start + x] = color_back;
-                        }
+                    for target_x in 0..target_width {
+                        let x = target_x / scale_x;
+                        let color =
+                            if is_horizontal_line || x == mid_x || (x % 10 == 0 && y % 10 == 0) {
+                                color_line
+                            } else {
+                                color_back
+                            };
+                        let pixel_index = row_start + target_x * samples;
+                        target.color[pixel_index..pixel_index + samples].fill(color);
                     }
                 }
             });
@@ -764,10 +1127,131 @@ pub fn render(
     // canvas.set_draw_color(Color::RGB(0, 0, 0));
     // canvas.clear();
     // clear_color_buffer(0xFFFF0000);
-    make_grid(0xFF505966, 0xFF292B2E, width, height);
-    render_entity(&memory.entity, &mut memory.camera);
+    prepare_render_target(
+        &mut memory.target,
+        &memory.render_settings,
+        &memory.view_settings,
+    );
+    memory
+        .frame_stats
+        .hiz_rejected_blocks
+        .store(0, Ordering::Relaxed);
+    // The skybox needs the camera, so it is drawn by the scene instead
+    if !memory.render_settings.use_skybox {
+        make_grid(0xFF505966, 0xFF292B2E, width, height);
+    }
+    render_scene(&mut memory.camera);
+    resolve_render_target();
+    if memory.render_settings.use_fxaa {
+        apply_fxaa();
+    }
     let _ = render_color_buffer(canvas, texture);
-    for i in 0..memory.z_buffer.len() {
-        memory.z_buffer[i] = 1.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::vector3_length;
+
+    fn get_weighted_point(weights: Vec3, points: [Vec3; 3]) -> Vec3 {
+        vector3_add(
+            vector3_add(
+                vector3_mul_float(points[0], weights.x),
+                vector3_mul_float(points[1], weights.y),
+            ),
+            vector3_mul_float(points[2], weights.z),
+        )
+    }
+
+    fn is_near(a: Vec3, b: Vec3) -> bool {
+        vector3_length(vector3_sub(a, b)) < 1e-5
+    }
+
+    #[test]
+    fn clip_triangle_corner_weights_rebuild_the_clipped_points() {
+        // Keeps x >= 0, which cuts the first corner off and leaves a quad
+        let plane = Plane {
+            position: Vec3::default(),
+            normal_dirrection: Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        let points = [
+            Vec3 {
+                x: -1.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 3.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 3.0,
+                y: 2.0,
+                z: 1.0,
+            },
+        ];
+        let polygon = clip_triangle(&[plane], points[0], points[1], points[2]);
+        assert_eq!(polygon.len, 4);
+        for (point, weights) in polygon
+            .points
+            .iter()
+            .zip(polygon.corner_weights)
+            .take(polygon.len)
+        {
+            assert!((weights.x + weights.y + weights.z - 1.0).abs() < 1e-5);
+            assert!(is_near(get_weighted_point(weights, points), *point));
+        }
+        // The first cut is on the edge from the third corner back to the first, a quarter of the
+        // way from the first
+        assert!(is_near(
+            polygon.corner_weights[0],
+            Vec3 {
+                x: 0.75,
+                y: 0.0,
+                z: 0.25,
+            }
+        ));
+    }
+
+    #[test]
+    fn unclipped_triangle_keeps_unit_corner_weights() {
+        let plane = Plane {
+            position: Vec3::default(),
+            normal_dirrection: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        };
+        let points = [
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        ];
+        let polygon = clip_triangle(&[plane], points[0], points[1], points[2]);
+        assert_eq!(polygon.len, 3);
+        for (corner, weights) in polygon.corner_weights.iter().take(3).enumerate() {
+            let components = [weights.x, weights.y, weights.z];
+            for (component, value) in components.into_iter().enumerate() {
+                assert_eq!(value, if component == corner { 1.0 } else { 0.0 });
+            }
+        }
     }
 }
@@ -0,0 +1,110 @@
+use crate::math::color_to_linear;
+use crate::types::{Texture, TextureAddressMode};
+
+pub fn apply_address_mode(coord: i32, size: u32, mode: TextureAddressMode) -> Option<u32> {
+    let size_i32 = size as i32;
+    match mode {
+        TextureAddressMode::Repeat => Some(coord.rem_euclid(size_i32) as u32),
+        TextureAddressMode::ClampToEdge => Some(coord.clamp(0, size_i32 - 1) as u32),
+        TextureAddressMode::MirroredRepeat => {
+            let period = coord.rem_euclid(size_i32 * 2);
+            if period < size_i32 {
+                Some(period as u32)
+            } else {
+                Some((size_i32 * 2 - 1 - period) as u32)
+            }
+        }
+        TextureAddressMode::Border => {
+            if coord >= 0 && coord < size_i32 {
+                Some(coord as u32)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub fn sample_texture(texture: &Texture, u: f32, v: f32) -> [f32; 4] {
+    let texel_x = (u * texture.width as f32).floor() as i32;
+    let texel_y = (v * texture.height as f32).floor() as i32;
+
+    let x = apply_address_mode(texel_x, texture.width, texture.address_mode_u);
+    let y = apply_address_mode(texel_y, texture.height, texture.address_mode_v);
+    match (x, y) {
+        (Some(x), Some(y)) => texture.data[(y * texture.width + x) as usize],
+        _ => color_to_linear(texture.border_color),
+    }
+}
+
+pub fn next_address_mode(mode: TextureAddressMode) -> TextureAddressMode {
+    match mode {
+        TextureAddressMode::Repeat => TextureAddressMode::ClampToEdge,
+        TextureAddressMode::ClampToEdge => TextureAddressMode::MirroredRepeat,
+        TextureAddressMode::MirroredRepeat => TextureAddressMode::Border,
+        TextureAddressMode::Border => TextureAddressMode::Repeat,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 4;
+
+    fn get_addressed(mode: TextureAddressMode) -> Vec<Option<u32>> {
+        (-5..9)
+            .map(|coord| apply_address_mode(coord, SIZE, mode))
+            .collect()
+    }
+
+    #[test]
+    fn repeat_wraps_around() {
+        let expected = [3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0].map(Some);
+        assert_eq!(get_addressed(TextureAddressMode::Repeat), expected);
+    }
+
+    #[test]
+    fn clamp_to_edge_holds_the_edge_texels() {
+        let expected = [0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3].map(Some);
+        assert_eq!(get_addressed(TextureAddressMode::ClampToEdge), expected);
+    }
+
+    #[test]
+    fn mirrored_repeat_flips_every_other_period() {
+        let expected = [3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0].map(Some);
+        assert_eq!(get_addressed(TextureAddressMode::MirroredRepeat), expected);
+    }
+
+    #[test]
+    fn border_only_addresses_texels_inside() {
+        let expected = [
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(0),
+            Some(1),
+            Some(2),
+            Some(3),
+        ]
+        .into_iter()
+        .chain([None; 5])
+        .collect::<Vec<_>>();
+        assert_eq!(get_addressed(TextureAddressMode::Border), expected);
+    }
+
+    #[test]
+    fn border_samples_the_border_color() {
+        let texture = Texture {
+            data: vec![[1.0; 4]; (SIZE * SIZE) as usize],
+            width: SIZE,
+            height: SIZE,
+            address_mode_u: TextureAddressMode::Border,
+            address_mode_v: TextureAddressMode::Repeat,
+            border_color: 0xFF000000,
+        };
+        assert_eq!(sample_texture(&texture, 0.5, 1.5), [1.0; 4]);
+        assert_eq!(sample_texture(&texture, 1.5, 0.5), [0.0, 0.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::math::{vector2_add, vector2_mul_float, vector3_add, vector3_mul_float};
+use crate::types::{Mesh, TextureUV, Vec3, VertexSemantic};
+
+pub const MAX_VARYINGS: usize = 20;
+
+// Values a vertex shader hands to the rasterizer, only the first `len` are interpolated
+#[derive(Clone, Copy)]
+pub struct Varyings {
+    pub values: [f32; MAX_VARYINGS],
+    pub len: usize,
+}
+
+pub const MAX_ATTRIBUTE_COMPONENTS: usize = 4;
+
+// Position is in model space and face normal in view space, both at the clipped corner
+pub struct VertexInput<'a> {
+    pub position: Vec3,
+    pub uv: TextureUV,
+    pub face_normal: Vec3,
+    mesh: &'a Mesh,
+    triangle_index: usize,
+    corner_weights: Vec3,
+}
+
+impl VertexInput<'_> {
+    // Blends the attribute like clipping blends positions, so clipped corners stay consistent
+    pub fn get_attribute(
+        &self,
+        semantic: VertexSemantic,
+    ) -> Option<[f32; MAX_ATTRIBUTE_COMPONENTS]> {
+        let attribute = self
+            .mesh
+            .attributes
+            .iter()
+            .find(|attribute| attribute.semantic == semantic)?;
+        let indices = attribute.indices[self.triangle_index];
+        let weights = [
+            self.corner_weights.x,
+            self.corner_weights.y,
+            self.corner_weights.z,
+        ];
+        let mut value = [0.0; MAX_ATTRIBUTE_COMPONENTS];
+        for (index, weight) in indices.into_iter().zip(weights) {
+            let start = index as usize * attribute.components;
+            let element = &attribute.values[start..start + attribute.components];
+            for (component, element_component) in value.iter_mut().zip(element) {
+                *component += element_component * weight;
+            }
+        }
+        Some(value)
+    }
+
+    // Set 0 is the triangle UVs, a mesh without the requested set falls back to it
+    pub fn get_uv(&self, uv_set: u8) -> TextureUV {
+        if uv_set == 0 {
+            return self.uv;
+        }
+        match self.get_attribute(VertexSemantic::TexCoord(uv_set)) {
+            Some([u, v, ..]) => TextureUV { u, v },
+            None => self.uv,
+        }
+    }
+
+    // Center of the source triangle, the same for every clipped corner
+    pub fn get_face_position(&self) -> Vec3 {
+        let triangle = &self.mesh.triangles[self.triangle_index];
+        let sum = vector3_add(
+            vector3_add(
+                self.mesh.vertices[triangle.a as usize - 1],
+                self.mesh.vertices[triangle.b as usize - 1],
+            ),
+            self.mesh.vertices[triangle.c as usize - 1],
+        );
+        vector3_mul_float(sum, 1.0 / 3.0)
+    }
+}
+
+// Steps from set 0 through the extra sets the mesh carries and back
+pub fn next_uv_set(mesh: &Mesh, uv_set: u8) -> u8 {
+    let next_set = uv_set.saturating_add(1);
+    let has_next_set = mesh
+        .attributes
+        .iter()
+        .any(|attribute| attribute.semantic == VertexSemantic::TexCoord(next_set));
+    if has_next_set {
+        next_set
+    } else {
+        0
+    }
+}
+
+// Positions are clipped and projected by the fixed pipeline, the shader only produces varyings.
+// It runs once on each clipped corner when the triangle is projected
+pub trait VertexShader {
+    fn shade_vertex(&self, input: &VertexInput) -> Varyings;
+}
+
+// Fragments are linear RGBA with straight alpha, or premultiplied for premultiplied
+// materials. The target encodes them to sRGB
+pub trait FragmentShader {
+    fn shade_fragment(&self, varyings: &Varyings) -> [f32; 4];
+}
+
+pub trait Shader: VertexShader + FragmentShader + Sync {}
+
+impl<T: VertexShader + FragmentShader + Sync> Shader for T {}
+
+// Varyings divided by w, which screen-space barycentric weights can interpolate linearly
+pub struct PerspectiveVaryings {
+    pub weighted: [Varyings; 3],
+    pub reciprocal_w: [f32; 3],
+}
+
+pub fn make_varyings(values: &[f32]) -> Varyings {
+    let mut varyings = Varyings {
+        values: [0.0; MAX_VARYINGS],
+        len: values.len(),
+    };
+    varyings.values[..values.len()].copy_from_slice(values);
+    varyings
+}
+
+pub fn push_varyings(varyings: &mut Varyings, values: &[f32]) {
+    varyings.values[varyings.len..varyings.len + values.len()].copy_from_slice(values);
+    varyings.len += values.len();
+}
+
+pub fn get_vertex_input(
+    mesh: &Mesh,
+    triangle_index: usize,
+    corner_weights: Vec3,
+    face_normal: Vec3,
+) -> VertexInput<'_> {
+    let triangle = &mesh.triangles[triangle_index];
+    let position = vector3_add(
+        vector3_add(
+            vector3_mul_float(mesh.vertices[triangle.a as usize - 1], corner_weights.x),
+            vector3_mul_float(mesh.vertices[triangle.b as usize - 1], corner_weights.y),
+        ),
+        vector3_mul_float(mesh.vertices[triangle.c as usize - 1], corner_weights.z),
+    );
+    let uv = vector2_add(
+        vector2_add(
+            vector2_mul_float(triangle.a_uv.into(), corner_weights.x),
+            vector2_mul_float(triangle.b_uv.into(), corner_weights.y),
+        ),
+        vector2_mul_float(triangle.c_uv.into(), corner_weights.z),
+    );
+    VertexInput {
+        position,
+        uv: uv.into(),
+        face_normal,
+        mesh,
+        triangle_index,
+        corner_weights,
+    }
+}
+
+pub fn get_perspective_varyings(varyings: &[Varyings; 3], w: [f32; 3]) -> PerspectiveVaryings {
+    let reciprocal_w = [1.0 / w[0], 1.0 / w[1], 1.0 / w[2]];
+    let mut weighted = *varyings;
+    for (vertex, vertex_varyings) in weighted.iter_mut().enumerate() {
+        for value in &mut vertex_varyings.values[..vertex_varyings.len] {
+            *value *= reciprocal_w[vertex];
+        }
+    }
+    PerspectiveVaryings {
+        weighted,
+        reciprocal_w,
+    }
+}
+
+pub fn interpolate_varyings(
+    perspective_varyings: &PerspectiveVaryings,
+    alpha: f32,
+    beta: f32,
+    gamma: f32,
+) -> Varyings {
+    let [w0, w1, w2] = &perspective_varyings.weighted;
+    let [reciprocal_w0, reciprocal_w1, reciprocal_w2] = perspective_varyings.reciprocal_w;
+    let interpolated_reciprocal_w =
+        reciprocal_w0 * alpha + reciprocal_w1 * beta + reciprocal_w2 * gamma;
+    let mut varyings = Varyings {
+        values: [0.0; MAX_VARYINGS],
+        len: w0.len,
+    };
+    for i in 0..w0.len {
+        varyings.values[i] = (w0.values[i] * alpha + w1.values[i] * beta + w2.values[i] * gamma)
+            / interpolated_reciprocal_w;
+    }
+    varyings
+}
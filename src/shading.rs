@@ -0,0 +1,452 @@
+use crate::environment::sample_environment;
+use crate::lighting::{get_light_incidence, ViewLight};
+use crate::math::{
+    color_apply_opacity, color_premultiply_alpha, color_to_linear, get_model_view_matrix,
+    get_normal_matrix, transform_direction, transform_vertex, vector3_add, vector3_cross,
+    vector3_dot, vector3_length, vector3_mul_float, vector3_normalize, vector3_sub,
+};
+use crate::matrix::{matrix4_transpose, Matrix4};
+use crate::sampler::sample_texture;
+use crate::shader::{
+    make_varyings, push_varyings, FragmentShader, Shader, Varyings, VertexInput, VertexShader,
+};
+use crate::shadow::{get_shadow_visibility, ShadowMap, MAX_SHADOW_MAPS};
+use crate::types::{
+    BlendMode, Entity, Environment, Material, RenderSettings, ShadingMode, Texture, Vec3,
+    VertexSemantic,
+};
+
+// Surface color comes from the texture, else the vertex colors, else the base color
+pub struct Surface<'a> {
+    pub material: &'a Material,
+    pub use_texture: bool,
+    pub base_color: u32,
+}
+
+// The lights of the frame and the transforms that bring the entity into their view space
+pub struct Lighting<'a> {
+    pub lights: &'a [ViewLight],
+    pub shadow_maps: &'a [ShadowMap],
+    pub ambient_light: f32,
+    pub model_view_matrix: Matrix4,
+    pub normal_matrix: Matrix4,
+    pub environment: &'a Environment,
+    pub view_to_world: Matrix4,
+}
+
+pub struct UnlitShader<'a> {
+    pub surface: Surface<'a>,
+}
+
+// Lights the center of the face, so every corner carries the same light
+pub struct FlatShader<'a> {
+    pub surface: Surface<'a>,
+    pub lighting: Lighting<'a>,
+}
+
+// Lights the vertex normals and lets the rasterizer interpolate the light
+pub struct GouraudShader<'a> {
+    pub surface: Surface<'a>,
+    pub lighting: Lighting<'a>,
+}
+
+// Interpolates view space normals and positions and lights every fragment, with a
+// Blinn-Phong specular term
+pub struct PhongShader<'a> {
+    pub surface: Surface<'a>,
+    pub lighting: Lighting<'a>,
+}
+
+// Every shading model starts with the surface varyings, uv then vertex color
+const UV_VARYING: usize = 0;
+const COLOR_VARYING: usize = 2;
+const SURFACE_VARYINGS: usize = 6;
+// Flat and Gouraud follow with the unshadowed light, and with shadows the vertex position, the
+// diffuse term of each shadowed light and then the N dot L of each for the shadow bias
+const LIGHT_VARYING: usize = SURFACE_VARYINGS;
+const SHADOW_POSITION_VARYING: usize = LIGHT_VARYING + 3;
+const SHADOW_DIFFUSE_VARYING: usize = SHADOW_POSITION_VARYING + 3;
+// Phong follows with the normal and position, and the tangent and handedness for normal maps
+const NORMAL_VARYING: usize = SURFACE_VARYINGS;
+const POSITION_VARYING: usize = NORMAL_VARYING + 3;
+const TANGENT_VARYING: usize = POSITION_VARYING + 3;
+
+fn get_surface_varyings(surface: &Surface, input: &VertexInput) -> Varyings {
+    let uv = input.get_uv(surface.material.uv_set);
+    let [red, green, blue, alpha] = input
+        .get_attribute(VertexSemantic::Color)
+        .unwrap_or_else(|| color_to_linear(surface.base_color));
+    make_varyings(&[uv.u, uv.v, red, green, blue, alpha])
+}
+
+fn get_varying_vec3(varyings: &Varyings, offset: usize) -> Vec3 {
+    let values = &varyings.values;
+    Vec3 {
+        x: values[offset],
+        y: values[offset + 1],
+        z: values[offset + 2],
+    }
+}
+
+fn get_surface_uv(varyings: &Varyings) -> (f32, f32) {
+    (varyings.values[UV_VARYING], varyings.values[UV_VARYING + 1])
+}
+
+fn get_surface_color(surface: &Surface, varyings: &Varyings) -> [f32; 4] {
+    if surface.use_texture {
+        let (u, v) = get_surface_uv(varyings);
+        sample_texture(&surface.material.texture, u, v)
+    } else {
+        let values = &varyings.values;
+        [
+            values[COLOR_VARYING],
+            values[COLOR_VARYING + 1],
+            values[COLOR_VARYING + 2],
+            values[COLOR_VARYING + 3],
+        ]
+    }
+}
+
+// Texels and vertex colors are straight, premultiplied materials get their color scaled here
+fn apply_material_opacity(material: &Material, color: [f32; 4]) -> [f32; 4] {
+    let color = color_apply_opacity(color, material.opacity);
+    if material.blend_mode == BlendMode::PremultipliedAlpha {
+        color_premultiply_alpha(color)
+    } else {
+        color
+    }
+}
+
+impl VertexShader for UnlitShader<'_> {
+    fn shade_vertex(&self, input: &VertexInput) -> Varyings {
+        get_surface_varyings(&self.surface, input)
+    }
+}
+
+impl FragmentShader for UnlitShader<'_> {
+    fn shade_fragment(&self, varyings: &Varyings) -> [f32; 4] {
+        let color = get_surface_color(&self.surface, varyings);
+        apply_material_opacity(self.surface.material, color)
+    }
+}
+
+// Shadowed lights are tested per fragment, so they only pass their diffuse term on
+fn push_light_varyings(
+    varyings: &mut Varyings,
+    lighting: &Lighting,
+    lit_position: Vec3,
+    normal: Vec3,
+    vertex_position: Vec3,
+) {
+    let mut light_sum = [lighting.ambient_light; 3];
+    let mut shadowed_diffuse = [0.0; MAX_SHADOW_MAPS];
+    let mut shadowed_lambert = [0.0; MAX_SHADOW_MAPS];
+    for light in lighting.lights {
+        let (to_light, attenuation) = get_light_incidence(light, lit_position);
+        let lambert = vector3_dot(normal, to_light).max(0.0);
+        let diffuse = lambert * attenuation;
+        match light.shadow_map {
+            Some(shadow_map) => {
+                shadowed_diffuse[shadow_map] = diffuse;
+                shadowed_lambert[shadow_map] = lambert;
+            }
+            None => {
+                for (channel, radiance) in light_sum.iter_mut().zip(light.radiance) {
+                    *channel += radiance * diffuse;
+                }
+            }
+        }
+    }
+    debug_assert_eq!(varyings.len, LIGHT_VARYING);
+    push_varyings(varyings, &light_sum);
+    if !lighting.shadow_maps.is_empty() {
+        push_varyings(
+            varyings,
+            &[vertex_position.x, vertex_position.y, vertex_position.z],
+        );
+        push_varyings(varyings, &shadowed_diffuse[..lighting.shadow_maps.len()]);
+        push_varyings(varyings, &shadowed_lambert[..lighting.shadow_maps.len()]);
+    }
+}
+
+fn get_lit_surface_color(surface: &Surface, lighting: &Lighting, varyings: &Varyings) -> [f32; 4] {
+    let [red, green, blue, alpha] = get_surface_color(surface, varyings);
+    let values = &varyings.values;
+    let mut light_sum = [
+        values[LIGHT_VARYING],
+        values[LIGHT_VARYING + 1],
+        values[LIGHT_VARYING + 2],
+    ];
+    if !lighting.shadow_maps.is_empty() {
+        let position = get_varying_vec3(varyings, SHADOW_POSITION_VARYING);
+        for light in lighting.lights {
+            let Some(shadow_map) = light.shadow_map else {
+                continue;
+            };
+            let diffuse = values[SHADOW_DIFFUSE_VARYING + shadow_map];
+            if diffuse > 0.0 {
+                let lambert =
+                    values[SHADOW_DIFFUSE_VARYING + lighting.shadow_maps.len() + shadow_map];
+                let visibility =
+                    get_shadow_visibility(&lighting.shadow_maps[shadow_map], position, lambert);
+                for (channel, radiance) in light_sum.iter_mut().zip(light.radiance) {
+                    *channel += radiance * diffuse * visibility;
+                }
+            }
+        }
+    }
+    apply_material_opacity(
+        surface.material,
+        [
+            red * light_sum[0],
+            green * light_sum[1],
+            blue * light_sum[2],
+            alpha,
+        ],
+    )
+}
+
+impl VertexShader for FlatShader<'_> {
+    fn shade_vertex(&self, input: &VertexInput) -> Varyings {
+        let mut varyings = get_surface_varyings(&self.surface, input);
+        let model_view_matrix = self.lighting.model_view_matrix;
+        push_light_varyings(
+            &mut varyings,
+            &self.lighting,
+            transform_vertex(input.get_face_position(), model_view_matrix),
+            input.face_normal,
+            transform_vertex(input.position, model_view_matrix),
+        );
+        varyings
+    }
+}
+
+impl FragmentShader for FlatShader<'_> {
+    fn shade_fragment(&self, varyings: &Varyings) -> [f32; 4] {
+        get_lit_surface_color(&self.surface, &self.lighting, varyings)
+    }
+}
+
+// View space vertex normal, the face normal stands in when the mesh has none
+fn get_vertex_normal(input: &VertexInput, normal_matrix: Matrix4) -> Vec3 {
+    let Some([x, y, z, _]) = input.get_attribute(VertexSemantic::Normal) else {
+        return input.face_normal;
+    };
+    let mut normal = transform_direction(Vec3 { x, y, z }, normal_matrix);
+    if vector3_length(normal) == 0.0 {
+        return input.face_normal;
+    }
+    vector3_normalize(&mut normal);
+    normal
+}
+
+impl VertexShader for GouraudShader<'_> {
+    fn shade_vertex(&self, input: &VertexInput) -> Varyings {
+        let mut varyings = get_surface_varyings(&self.surface, input);
+        let normal = get_vertex_normal(input, self.lighting.normal_matrix);
+        let position = transform_vertex(input.position, self.lighting.model_view_matrix);
+        push_light_varyings(&mut varyings, &self.lighting, position, normal, position);
+        varyings
+    }
+}
+
+impl FragmentShader for GouraudShader<'_> {
+    fn shade_fragment(&self, varyings: &Varyings) -> [f32; 4] {
+        get_lit_surface_color(&self.surface, &self.lighting, varyings)
+    }
+}
+
+impl VertexShader for PhongShader<'_> {
+    fn shade_vertex(&self, input: &VertexInput) -> Varyings {
+        let mut varyings = get_surface_varyings(&self.surface, input);
+        let normal = get_vertex_normal(input, self.lighting.normal_matrix);
+        let position = transform_vertex(input.position, self.lighting.model_view_matrix);
+        debug_assert_eq!(varyings.len, NORMAL_VARYING);
+        push_varyings(
+            &mut varyings,
+            &[
+                normal.x, normal.y, normal.z, position.x, position.y, position.z,
+            ],
+        );
+        // Tangents move with the surface, so they take the model-view and not the normal matrix
+        if self.surface.material.normal_map.is_some() {
+            let [x, y, z, handedness] = input
+                .get_attribute(VertexSemantic::Tangent)
+                .unwrap_or_default();
+            let tangent = transform_direction(Vec3 { x, y, z }, self.lighting.model_view_matrix);
+            debug_assert_eq!(varyings.len, TANGENT_VARYING);
+            push_varyings(
+                &mut varyings,
+                &[tangent.x, tangent.y, tangent.z, handedness],
+            );
+        }
+        varyings
+    }
+}
+
+// Tangent space normal from the map, rebuilt on a tangent frame orthogonalized per fragment
+fn apply_normal_map(normal_map: &Texture, normal: Vec3, varyings: &Varyings) -> Vec3 {
+    let tangent_varying = get_varying_vec3(varyings, TANGENT_VARYING);
+    let mut tangent = vector3_sub(
+        tangent_varying,
+        vector3_mul_float(normal, vector3_dot(normal, tangent_varying)),
+    );
+    if vector3_length(tangent) == 0.0 {
+        return normal;
+    }
+    vector3_normalize(&mut tangent);
+    let handedness = if varyings.values[TANGENT_VARYING + 3] < 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+    let bitangent = vector3_mul_float(vector3_cross(normal, tangent), handedness);
+
+    let (u, v) = get_surface_uv(varyings);
+    let [red, green, blue, _] = sample_texture(normal_map, u, v);
+    let mut mapped_normal = vector3_add(
+        vector3_add(
+            vector3_mul_float(tangent, red * 2.0 - 1.0),
+            vector3_mul_float(bitangent, green * 2.0 - 1.0),
+        ),
+        vector3_mul_float(normal, blue * 2.0 - 1.0),
+    );
+    if vector3_length(mapped_normal) == 0.0 {
+        return normal;
+    }
+    vector3_normalize(&mut mapped_normal);
+    mapped_normal
+}
+
+impl FragmentShader for PhongShader<'_> {
+    fn shade_fragment(&self, varyings: &Varyings) -> [f32; 4] {
+        let color = get_surface_color(&self.surface, varyings);
+        // Interpolation shortens the normals, they have to be normalized again per fragment
+        let mut normal = get_varying_vec3(varyings, NORMAL_VARYING);
+        if vector3_length(normal) > 0.0 {
+            vector3_normalize(&mut normal);
+        }
+        if let Some(normal_map) = &self.surface.material.normal_map {
+            normal = apply_normal_map(normal_map, normal, varyings);
+        }
+        let position = get_varying_vec3(varyings, POSITION_VARYING);
+        // The camera sits at the view space origin
+        let mut to_eye = Vec3 {
+            x: -position.x,
+            y: -position.y,
+            z: -position.z,
+        };
+        if vector3_length(to_eye) > 0.0 {
+            vector3_normalize(&mut to_eye);
+        }
+
+        let material = self.surface.material;
+        let mut diffuse_light = [self.lighting.ambient_light; 3];
+        let mut specular_light = [0.0; 3];
+        for light in self.lighting.lights {
+            let (to_light, mut attenuation) = get_light_incidence(light, position);
+            let lambert = vector3_dot(normal, to_light).max(0.0);
+            if lambert == 0.0 || attenuation == 0.0 {
+                continue;
+            }
+            if let Some(shadow_map) = light.shadow_map {
+                attenuation *= get_shadow_visibility(
+                    &self.lighting.shadow_maps[shadow_map],
+                    position,
+                    lambert,
+                );
+            }
+            let mut half_vector = vector3_add(to_light, to_eye);
+            let specular = if vector3_length(half_vector) > 0.0 {
+                vector3_normalize(&mut half_vector);
+                vector3_dot(normal, half_vector)
+                    .max(0.0)
+                    .powf(material.shininess)
+            } else {
+                0.0
+            };
+            for ((diffuse_channel, specular_channel), radiance) in diffuse_light
+                .iter_mut()
+                .zip(specular_light.iter_mut())
+                .zip(light.radiance)
+            {
+                let radiance = radiance * attenuation;
+                *diffuse_channel += radiance * lambert;
+                *specular_channel += radiance * specular;
+            }
+        }
+
+        let [red, green, blue, alpha] = color;
+        let [specular_red, specular_green, specular_blue, _] =
+            color_to_linear(material.specular_color);
+        let mut lit_channels = [
+            red * diffuse_light[0] + specular_red * specular_light[0],
+            green * diffuse_light[1] + specular_green * specular_light[1],
+            blue * diffuse_light[2] + specular_blue * specular_light[2],
+            alpha,
+        ];
+        if material.reflectivity > 0.0 {
+            let reflection = get_reflection(&self.lighting, normal, to_eye);
+            for (channel, reflection_channel) in lit_channels[..3].iter_mut().zip(reflection) {
+                *channel += (reflection_channel - *channel) * material.reflectivity;
+            }
+        }
+        apply_material_opacity(material, lit_channels)
+    }
+}
+
+// Environment seen in the mirror direction of the eye ray
+fn get_reflection(lighting: &Lighting, normal: Vec3, to_eye: Vec3) -> [f32; 4] {
+    let reflected = vector3_sub(
+        vector3_mul_float(normal, 2.0 * vector3_dot(normal, to_eye)),
+        to_eye,
+    );
+    let world_direction = transform_direction(reflected, lighting.view_to_world);
+    sample_environment(lighting.environment, world_direction)
+}
+
+pub fn get_entity_shader<'a>(
+    entity: &'a Entity,
+    render_settings: &RenderSettings,
+    lights: &'a [ViewLight],
+    shadow_maps: &'a [ShadowMap],
+    ambient_light: f32,
+    environment: &'a Environment,
+    view_matrix: Matrix4,
+) -> Box<dyn Shader + 'a> {
+    let surface = Surface {
+        material: &entity.material,
+        use_texture: render_settings.use_textures,
+        base_color: render_settings.default_render_color,
+    };
+    if !render_settings.use_lighting {
+        return Box::new(UnlitShader { surface });
+    }
+    let lighting = Lighting {
+        lights,
+        shadow_maps,
+        ambient_light,
+        model_view_matrix: get_model_view_matrix(
+            entity.rotation,
+            entity.scale,
+            entity.translation,
+            view_matrix,
+        ),
+        normal_matrix: get_normal_matrix(entity.rotation, entity.scale, view_matrix),
+        environment,
+        view_to_world: matrix4_transpose(view_matrix),
+    };
+    match entity.material.shading_mode {
+        ShadingMode::Flat => Box::new(FlatShader { surface, lighting }),
+        ShadingMode::Gouraud => Box::new(GouraudShader { surface, lighting }),
+        ShadingMode::Phong => Box::new(PhongShader { surface, lighting }),
+    }
+}
+
+pub fn next_shading_mode(shading_mode: ShadingMode) -> ShadingMode {
+    match shading_mode {
+        ShadingMode::Flat => ShadingMode::Gouraud,
+        ShadingMode::Gouraud => ShadingMode::Phong,
+        ShadingMode::Phong => ShadingMode::Flat,
+    }
+}
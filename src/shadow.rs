@@ -0,0 +1,403 @@
+use crate::depth::get_depth_state;
+use crate::game_state::{generate_culling_planes, get_game_memory};
+use crate::hierarchical_depth::get_hiz_size;
+use crate::lighting::ViewLight;
+use crate::math::{
+    get_model_view_matrix, perspective_project_point, transform_direction, transform_vertex,
+    vector3_add, vector3_dot, vector3_length, vector3_mul_float, vector3_sub,
+};
+use crate::matrix::{get_look_at_view_matrix, get_projection_matrix, matrix4_mul_matrix4, Matrix4};
+use crate::render::{project_entity, ProjectedTriangle};
+use crate::shader::{make_varyings, FragmentShader, Varyings, VertexInput, VertexShader};
+use crate::subpixel_rendering::subpixel_render_triangle;
+use crate::tile_rendering::render_tiles;
+use crate::types::{
+    BlendMode, DepthCompare, DepthFormat, FrameStats, LightType, RenderTarget, Vec2, Vec3,
+};
+use crate::vector::vector4_snap;
+use std::sync::atomic::AtomicU32;
+
+// Flat and Gouraud carry two light terms per shadow map, which have to fit in the varyings
+pub const MAX_SHADOW_MAPS: usize = 4;
+// Keeps the slope bias finite for receivers that are nearly edge-on to the light
+const MAX_SHADOW_SLOPE: f32 = 10.0;
+
+// Linear depth seen from a light, indexed by the light's screen position
+pub struct ShadowMap {
+    pub view_to_light: Matrix4,
+    pub projection_matrix: Matrix4,
+    pub size: u32,
+    pub depth: Vec<f32>,
+    // Width of a texel at unit distance from the light
+    pub texel_scale: f32,
+    pub depth_bias: f32,
+    pub slope_bias: f32,
+    pub pcf_radius: i32,
+}
+
+struct ShadowCamera {
+    view_to_light: Matrix4,
+    fov: f32,
+    z_near: f32,
+    z_far: f32,
+}
+
+// Only depth is written, so every fragment is opaque and nothing gets alpha discarded
+pub struct DepthShader;
+
+impl VertexShader for DepthShader {
+    fn shade_vertex(&self, _input: &VertexInput) -> Varyings {
+        make_varyings(&[])
+    }
+}
+
+impl FragmentShader for DepthShader {
+    fn shade_fragment(&self, _varyings: &Varyings) -> [f32; 4] {
+        [0.0, 0.0, 0.0, 1.0]
+    }
+}
+
+// View space sphere around everything that casts shadows
+fn get_scene_bounds(view_matrix: Matrix4) -> Option<(Vec3, f32)> {
+    let game_memory = get_game_memory();
+    let mut min = Vec3 {
+        x: f32::MAX,
+        y: f32::MAX,
+        z: f32::MAX,
+    };
+    let mut max = Vec3 {
+        x: f32::MIN,
+        y: f32::MIN,
+        z: f32::MIN,
+    };
+    for entity in &game_memory.entities {
+        if entity.material.blend_mode != BlendMode::Opaque || entity.mesh.vertices.is_empty() {
+            continue;
+        }
+        let model_view_matrix = get_model_view_matrix(
+            entity.rotation,
+            entity.scale,
+            entity.translation,
+            view_matrix,
+        );
+        for vertex in &entity.mesh.vertices {
+            let point = transform_vertex(*vertex, model_view_matrix);
+            min = Vec3 {
+                x: min.x.min(point.x),
+                y: min.y.min(point.y),
+                z: min.z.min(point.z),
+            };
+            max = Vec3 {
+                x: max.x.max(point.x),
+                y: max.y.max(point.y),
+                z: max.z.max(point.z),
+            };
+        }
+    }
+    if min.x > max.x {
+        return None;
+    }
+    let center = vector3_mul_float(vector3_add(min, max), 0.5);
+    Some((center, vector3_length(vector3_sub(max, center))))
+}
+
+// Directional lights get a narrow perspective from far away, which is close to parallel
+// and keeps the rasterizer's 1/w depth meaningful
+fn get_shadow_camera(
+    light: &ViewLight,
+    scene_bounds: (Vec3, f32),
+    view_matrix: Matrix4,
+) -> Option<ShadowCamera> {
+    let (position, fov, z_near, z_far) = match light.light_type {
+        LightType::Directional => {
+            let (center, radius) = scene_bounds;
+            let distance = radius * 10.0;
+            (
+                vector3_sub(center, vector3_mul_float(light.direction, distance)),
+                (radius / distance).asin() * 2.0,
+                distance - radius,
+                distance + radius,
+            )
+        }
+        LightType::Spot => (
+            light.position,
+            light.cos_outer_cone.clamp(-1.0, 1.0).acos() * 2.0,
+            0.05,
+            light.range,
+        ),
+        LightType::Point => return None,
+    };
+    if !(fov > 0.0 && fov < std::f32::consts::PI && z_near > 0.0 && z_far > z_near) {
+        return None;
+    }
+
+    // World up, unless the light looks straight up or down
+    let mut up = transform_direction(
+        Vec3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        view_matrix,
+    );
+    if vector3_dot(up, light.direction).abs() > 0.99 {
+        up = transform_direction(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            view_matrix,
+        );
+    }
+    Some(ShadowCamera {
+        view_to_light: get_look_at_view_matrix(
+            position,
+            vector3_add(position, light.direction),
+            up,
+        ),
+        fov,
+        z_near,
+        z_far,
+    })
+}
+
+// Renders the opaque entities into a depth only target that takes the main target's place
+fn render_shadow_map(camera: &ShadowCamera, view_matrix: Matrix4) -> ShadowMap {
+    let game_memory = get_game_memory();
+    let render_settings = &game_memory.render_settings;
+    let size = render_settings.shadow_map_size;
+    let (hiz_width, hiz_height) = get_hiz_size(size, size);
+    let depth_state = get_depth_state(
+        DepthFormat::LinearView,
+        DepthCompare::Less,
+        camera.z_near,
+        camera.z_far,
+    );
+    let mut shadow_target = RenderTarget {
+        width: size,
+        height: size,
+        scale_x: 1,
+        scale_y: 1,
+        samples: 1,
+        color_write: false,
+        color: vec![],
+        depth: vec![depth_state.clear_value; (size * size) as usize],
+        depth_state,
+        hiz_width,
+        hiz: vec![depth_state.clear_value; (hiz_width * hiz_height) as usize],
+    };
+    let projection_matrix = get_projection_matrix(camera.fov, 1.0, camera.z_near, camera.z_far);
+    let planes = generate_culling_planes(
+        Vec2 {
+            x: camera.fov,
+            y: camera.fov,
+        },
+        camera.z_near,
+        camera.z_far,
+    );
+    let light_view_matrix = matrix4_mul_matrix4(camera.view_to_light, view_matrix);
+
+    std::mem::swap(&mut game_memory.target, &mut shadow_target);
+    let mut triangles: Vec<ProjectedTriangle> = vec![];
+    for (entity_index, entity) in game_memory.entities.iter().enumerate() {
+        if entity.material.blend_mode == BlendMode::Opaque {
+            triangles.extend(project_entity(
+                entity,
+                entity_index,
+                light_view_matrix,
+                projection_matrix,
+                &planes,
+                &DepthShader,
+            ));
+        }
+    }
+    // Shadows are filled the same whatever mode the view is drawn in, and the frame stats only
+    // count blocks of the main target
+    let entities = &game_memory.entities;
+    let shadow_stats = FrameStats {
+        hiz_rejected_blocks: AtomicU32::new(0),
+    };
+    render_tiles(
+        &mut game_memory.target,
+        &triangles,
+        |target, projected_triangle, clip| {
+            subpixel_render_triangle(
+                target,
+                projected_triangle
+                    .points
+                    .map(|point| vector4_snap(point, render_settings.subpixel_bits)),
+                &projected_triangle.varyings,
+                &DepthShader,
+                &entities[projected_triangle.entity_index].material,
+                None,
+                clip,
+                render_settings,
+                &shadow_stats,
+            );
+        },
+    );
+    std::mem::swap(&mut game_memory.target, &mut shadow_target);
+
+    ShadowMap {
+        view_to_light: camera.view_to_light,
+        projection_matrix,
+        size,
+        depth: shadow_target.depth,
+        texel_scale: 2.0 * (camera.fov / 2.0).tan() / size as f32,
+        depth_bias: render_settings.shadow_depth_bias,
+        slope_bias: render_settings.shadow_slope_bias,
+        pcf_radius: render_settings.shadow_pcf_radius as i32,
+    }
+}
+
+// Gives each shadow casting light its map, in light order
+pub fn render_shadow_maps(lights: &mut [ViewLight], view_matrix: Matrix4) -> Vec<ShadowMap> {
+    let mut shadow_maps = vec![];
+    if !get_game_memory().render_settings.use_shadows {
+        return shadow_maps;
+    }
+    let Some(scene_bounds) = get_scene_bounds(view_matrix) else {
+        return shadow_maps;
+    };
+    for light in lights.iter_mut().filter(|light| light.casts_shadows) {
+        if shadow_maps.len() == MAX_SHADOW_MAPS {
+            break;
+        }
+        if let Some(camera) = get_shadow_camera(light, scene_bounds, view_matrix) {
+            light.shadow_map = Some(shadow_maps.len());
+            shadow_maps.push(render_shadow_map(&camera, view_matrix));
+        }
+    }
+    shadow_maps
+}
+
+// Fraction of the filter footprint that sees the light, points outside the map are lit.
+// The receiver's N dot L scales the slope bias
+pub fn get_shadow_visibility(shadow_map: &ShadowMap, position: Vec3, n_dot_l: f32) -> f32 {
+    let light_position = transform_vertex(position, shadow_map.view_to_light);
+    if light_position.z <= 0.0 {
+        return 1.0;
+    }
+    let projected = perspective_project_point(
+        light_position,
+        shadow_map.projection_matrix,
+        shadow_map.size,
+        shadow_map.size,
+    );
+    // A texel covers more depth the more the surface tilts away from the light, by the
+    // tangent of the angle between the normal and the light
+    let n_dot_l = n_dot_l.clamp(f32::EPSILON, 1.0);
+    let slope = ((1.0 - n_dot_l * n_dot_l).sqrt() / n_dot_l).min(MAX_SHADOW_SLOPE);
+    let texel_size = shadow_map.texel_scale * light_position.z;
+    let bias = shadow_map.depth_bias + shadow_map.slope_bias * texel_size * slope;
+    let depth = light_position.z - bias;
+
+    let size = shadow_map.size as i32;
+    let center_x = projected.x.floor() as i32;
+    let center_y = projected.y.floor() as i32;
+    let radius = shadow_map.pcf_radius;
+    let mut lit_samples = 0;
+    for y in center_y - radius..=center_y + radius {
+        for x in center_x - radius..=center_x + radius {
+            if x < 0
+                || y < 0
+                || x >= size
+                || y >= size
+                || depth <= shadow_map.depth[(y * size + x) as usize]
+            {
+                lit_samples += 1;
+            }
+        }
+    }
+    let sample_count = (radius * 2 + 1) * (radius * 2 + 1);
+    lit_samples as f32 / sample_count as f32
+}
+
+pub fn next_pcf_radius(radius: u32) -> u32 {
+    (radius + 1) % 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::get_matrix4_identity;
+
+    const SIZE: u32 = 8;
+    const OCCLUDER_DEPTH: f32 = 10.0;
+
+    // Looks down +z with a 90 degree frustum, so the view axis lands on texel (4, 4)
+    fn get_shadow_map(depth: Vec<f32>) -> ShadowMap {
+        let fov = std::f32::consts::FRAC_PI_2;
+        ShadowMap {
+            view_to_light: get_matrix4_identity(),
+            projection_matrix: get_projection_matrix(fov, 1.0, 0.1, 100.0),
+            size: SIZE,
+            depth,
+            texel_scale: 2.0 * (fov / 2.0).tan() / SIZE as f32,
+            depth_bias: 0.0,
+            slope_bias: 0.0,
+            pcf_radius: 0,
+        }
+    }
+
+    fn get_point(x: f32, z: f32) -> Vec3 {
+        Vec3 { x, y: 0.0, z }
+    }
+
+    #[test]
+    fn occluder_shadows_only_the_points_behind_it() {
+        let shadow_map = get_shadow_map(vec![OCCLUDER_DEPTH; (SIZE * SIZE) as usize]);
+        assert_eq!(
+            get_shadow_visibility(&shadow_map, get_point(0.0, 5.0), 1.0),
+            1.0
+        );
+        assert_eq!(
+            get_shadow_visibility(&shadow_map, get_point(0.0, 20.0), 1.0),
+            0.0
+        );
+    }
+
+    #[test]
+    fn points_outside_the_map_are_lit() {
+        let shadow_map = get_shadow_map(vec![OCCLUDER_DEPTH; (SIZE * SIZE) as usize]);
+        assert_eq!(
+            get_shadow_visibility(&shadow_map, get_point(0.0, -5.0), 1.0),
+            1.0
+        );
+        assert_eq!(
+            get_shadow_visibility(&shadow_map, get_point(100.0, 20.0), 1.0),
+            1.0
+        );
+    }
+
+    #[test]
+    fn pcf_averages_the_texels_around_the_point() {
+        // The left half of the map is covered, the right half sees the light
+        let depth = (0..SIZE * SIZE)
+            .map(|index| {
+                if index % SIZE < SIZE / 2 {
+                    OCCLUDER_DEPTH
+                } else {
+                    f32::MAX
+                }
+            })
+            .collect();
+        let mut shadow_map = get_shadow_map(depth);
+        shadow_map.pcf_radius = 1;
+        let visibility = get_shadow_visibility(&shadow_map, get_point(0.0, 20.0), 1.0);
+        assert!((visibility - 6.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn slope_bias_grows_as_the_surface_tilts_away() {
+        let mut shadow_map = get_shadow_map(vec![OCCLUDER_DEPTH; (SIZE * SIZE) as usize]);
+        shadow_map.slope_bias = 1.0;
+        let receiver = get_point(0.0, OCCLUDER_DEPTH + 0.3);
+        assert_eq!(get_shadow_visibility(&shadow_map, receiver, 1.0), 0.0);
+        assert_eq!(get_shadow_visibility(&shadow_map, receiver, 0.5), 1.0);
+        shadow_map.slope_bias = 0.0;
+        shadow_map.depth_bias = 0.5;
+        assert_eq!(get_shadow_visibility(&shadow_map, receiver, 1.0), 1.0);
+    }
+}
@@ -1,23 +1,33 @@
-use crate::types::{Camera, Entity, IntVec2, Plane, Texture, TextureUV, Vec2, Vec3};
-use crate::vector::{vector4_trunk, FixedVec4, Vec4};
-use fixed::types::extra::U16;
-use fixed::FixedI64;
+use crate::types::{
+    Camera, DepthFormat, Entity, FrameStats, IntVec2, Material, Plane, RenderSettings, ScreenRect,
+    TargetView, Vec2, Vec3,
+};
+use crate::vector::{vector4_trunk, SubpixelVec4, Vec4};
 use std::cmp;
 use std::cmp::max;
 use std::cmp::min;
+use std::sync::atomic::Ordering;
 use std::thread;
-type Fixed = FixedI64<U16>;
 
-use crate::game_state::{get_color_buffer, get_game_memory, HEIGHT, WIDTH};
+pub const MIN_SUBPIXEL_BITS: u32 = 4;
+pub const MAX_SUBPIXEL_BITS: u32 = 16;
+
+use crate::anti_aliasing::{get_msaa_sample_pattern, MAX_MSAA_SAMPLES};
+use crate::depth::{is_depth_test_passed, reciprocal_w_to_depth};
+use crate::edge_functions::{evaluate_edge_point, evaluate_edge_span, EdgeSetup, EDGE_LANES};
+use crate::fog::{apply_fog, Fog};
+use crate::hierarchical_depth::{is_block_occluded, update_hiz_block, HIZ_BLOCK_SIZE};
 use crate::math::{
-    barycentric_weights, get_inv_slope, light_apply_intensity, perspective_project_point,
-    transform_vertex, triangle_avg, triangle_midpoint_uv, triangle_vec2_midpoint,
+    barycentric_weights, blend_colors, get_inv_slope, is_alpha_discarded,
+    perspective_project_point, transform_vertex, triangle_avg, triangle_vec2_midpoint,
     triangle_vec4_midpoint, vector2_add, vector2_mul_float, vector2_sub, vector3_add,
     vector3_cross, vector3_dot, vector3_mul, vector3_mul_float, vector3_normalize, vector3_sub,
 };
 use crate::matrix::{get_fps_view_matrix, get_projection_matrix, Matrix4};
+use crate::render::render_fragment;
+use crate::shader::{get_perspective_varyings, interpolate_varyings, FragmentShader, Varyings};
 
-pub fn triangle_cross(a: &FixedVec4, b: &FixedVec4, c: &FixedVec4) -> Fixed {
+pub fn triangle_cross(a: &SubpixelVec4, b: &SubpixelVec4, c: &SubpixelVec4) -> i64 {
     let ab_x = b.x - a.x;
     let ab_y = b.y - a.y;
     let ac_x = c.x - a.x;
@@ -28,7 +38,7 @@ pub fn triangle_cross(a: &FixedVec4, b: &FixedVec4, c: &FixedVec4) -> Fixed {
     res
 }
 
-pub fn is_edge_top_left(p0: &FixedVec4, p1: &FixedVec4) -> bool {
+pub fn is_edge_top_left(p0: &SubpixelVec4, p1: &SubpixelVec4) -> bool {
     let edge_x = p1.x - p0.x;
     let edge_y = p1.y - p0.y;
     let is_edge_top = edge_y == 0 && edge_x > 0;
@@ -37,133 +47,309 @@ pub fn is_edge_top_left(p0: &FixedVec4, p1: &FixedVec4) -> bool {
     res
 }
 
+pub fn next_subpixel_bits(subpixel_bits: u32) -> u32 {
+    if subpixel_bits >= MAX_SUBPIXEL_BITS {
+        MIN_SUBPIXEL_BITS
+    } else {
+        subpixel_bits * 2
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn subpixel_render_triangle(
-    p0: FixedVec4,
-    p1: FixedVec4,
-    p2: FixedVec4,
-    uv0: &TextureUV,
-    uv1: &TextureUV,
-    uv2: &TextureUV,
-    texture: &Texture,
-    color: u32,
-    light_dot: f32,
+    target: &mut TargetView,
+    points: [SubpixelVec4; 3],
+    varyings: &[Varyings; 3],
+    shader: &dyn FragmentShader,
+    material: &Material,
+    fog: Option<&Fog>,
+    clip: &ScreenRect,
+    render_settings: &RenderSettings,
+    frame_stats: &FrameStats,
 ) {
-    let x_min: i32 = (p0
-        .x
-        .to_num::<f32>()
-        .min(p1.x.to_num::<f32>())
-        .min(p2.x.to_num::<f32>()))
-    .round() as i32;
-    let x_max: i32 = (p0
-        .x
-        .to_num::<f32>()
-        .max(p1.x.to_num::<f32>())
-        .max(p2.x.to_num::<f32>()))
-    .round() as i32;
-    let y_min: i32 = (p0
-        .y
-        .to_num::<f32>()
-        .min(p1.y.to_num::<f32>())
-        .min(p2.y.to_num::<f32>()))
-    .round() as i32;
-    let y_max: i32 = (p0
-        .y
-        .to_num::<f32>()
-        .max(p1.y.to_num::<f32>())
-        .max(p2.y.to_num::<f32>()))
-    .round() as i32;
+    let [p0, p1, p2] = points;
+    let target_width = target.width as i32;
+    let samples = target.samples;
+
+    let subpixel_bits = render_settings.subpixel_bits;
+    let half_pixel = 1i64 << (subpixel_bits - 1);
+    let to_pixel = |coord: i64| ((coord + half_pixel) >> subpixel_bits) as i32;
+    let mut x_min = to_pixel(p0.x.min(p1.x).min(p2.x));
+    let mut x_max = to_pixel(p0.x.max(p1.x).max(p2.x));
+    let mut y_min = to_pixel(p0.y.min(p1.y).min(p2.y));
+    let mut y_max = to_pixel(p0.y.max(p1.y).max(p2.y));
+    // MSAA samples reach up to half a pixel past the pixel center
+    if samples > 1 {
+        x_min -= 1;
+        y_min -= 1;
+        x_max += 1;
+        y_max += 1;
+    }
+    x_min = max(x_min, clip.x_min);
+    y_min = max(y_min, clip.y_min);
+    x_max = min(x_max, clip.x_max);
+    y_max = min(y_max, clip.y_max);
 
     let triangle_area = triangle_cross(&p0, &p1, &p2);
+    if triangle_area == 0 || x_min >= x_max || y_min >= y_max {
+        return;
+    }
 
-    let delta_w0_col = p1.y - p2.y;
-    let delta_w1_col = p2.y - p0.y;
-    let delta_w2_col = p0.y - p1.y;
+    // Edge values change by these per subpixel unit, and by the shifted ones per pixel
+    let edge0_dx = p1.y - p2.y;
+    let edge1_dx = p2.y - p0.y;
+    let edge2_dx = p0.y - p1.y;
 
-    let delta_w0_row = p2.x - p1.x;
-    let delta_w1_row = p0.x - p2.x;
-    let delta_w2_row = p1.x - p0.x;
+    let edge0_dy = p2.x - p1.x;
+    let edge1_dy = p0.x - p2.x;
+    let edge2_dy = p1.x - p0.x;
 
-    let threashhold: Fixed = Fixed::from_num(0.001);
-    let mut bias0: Fixed = Fixed::from_num(0.0);
-    if is_edge_top_left(&p1, &p2) {
-        bias0 -= threashhold;
-    }
-    let mut bias1: Fixed = Fixed::from_num(0.0);
-    if is_edge_top_left(&p2, &p0) {
-        bias1 -= threashhold;
-    }
-    let mut bias2: Fixed = Fixed::from_num(0.0);
-    if is_edge_top_left(&p0, &p1) {
-        bias2 -= threashhold;
+    let delta_w0_col = edge0_dx << subpixel_bits;
+    let delta_w1_col = edge1_dx << subpixel_bits;
+    let delta_w2_col = edge2_dx << subpixel_bits;
+
+    let delta_w0_row = edge0_dy << subpixel_bits;
+    let delta_w1_row = edge1_dy << subpixel_bits;
+    let delta_w2_row = edge2_dy << subpixel_bits;
+
+    // Edge functions are exact integers, so one unit is enough to break ties on shared edges
+    let bias0 = if is_edge_top_left(&p1, &p2) { -1 } else { 0 };
+    let bias1 = if is_edge_top_left(&p2, &p0) { -1 } else { 0 };
+    let bias2 = if is_edge_top_left(&p0, &p1) { -1 } else { 0 };
+
+    let mut sample_offsets = [(0i64, 0i64, 0i64); MAX_MSAA_SAMPLES];
+    let sample_pattern = get_msaa_sample_pattern(samples);
+    for (i, (offset_x, offset_y)) in sample_pattern.iter().enumerate() {
+        // Sample patterns are in 1/16 pixel units
+        let offset_x = (*offset_x as i64) << (subpixel_bits - 4);
+        let offset_y = (*offset_y as i64) << (subpixel_bits - 4);
+        sample_offsets[i] = (
+            offset_x * edge0_dx + offset_y * edge0_dy,
+            offset_x * edge1_dx + offset_y * edge1_dy,
+            offset_x * edge2_dx + offset_y * edge2_dy,
+        );
     }
 
-    let p_target = FixedVec4 {
-        x: Fixed::from_num(x_min as f32 + 0.5),
-        y: Fixed::from_num(y_min as f32 + 0.5),
-        z: Fixed::from_num(0.5),
-        w: Fixed::from_num(0.5),
+    let p_target = SubpixelVec4 {
+        x: ((x_min as i64) << subpixel_bits) + half_pixel,
+        y: ((y_min as i64) << subpixel_bits) + half_pixel,
+        z: 0.5,
+        w: 0.5,
     };
 
-    let mut w0_row = triangle_cross(&p1, &p2, &p_target) + bias0;
-    let mut w1_row = triangle_cross(&p2, &p0, &p_target) + bias1;
-    let mut w2_row = triangle_cross(&p0, &p1, &p_target) + bias2;
+    let depth_state = target.depth_state;
+    let perspective_varyings = get_perspective_varyings(varyings, [p0.w, p1.w, p2.w]);
+    let [reciprocal_w0, reciprocal_w1, reciprocal_w2] = perspective_varyings.reciprocal_w;
+    let edge_setup = EdgeSetup {
+        delta_col: [
+            delta_w0_col as f64,
+            delta_w1_col as f64,
+            delta_w2_col as f64,
+        ],
+        reciprocal_area: 1.0 / triangle_area as f64,
+        reciprocal_w: [
+            reciprocal_w0 as f64,
+            reciprocal_w1 as f64,
+            reciprocal_w2 as f64,
+        ],
+        depth_scale: depth_state.scale,
+        depth_offset: depth_state.offset,
+        is_depth_linear: depth_state.format == DepthFormat::LinearView,
+    };
+    let edge_values = |w0: i64, w1: i64, w2: i64| -> [f64; 3] { [w0 as f64, w1 as f64, w2 as f64] };
+    let shade = |x: i32, y: i32, alpha: f32, beta: f32, gamma: f32| -> [f32; 4] {
+        let color = shader.shade_fragment(&interpolate_varyings(
+            &perspective_varyings,
+            alpha,
+            beta,
+            gamma,
+        ));
+        let Some(fog) = fog else {
+            return color;
+        };
+        let reciprocal_w = reciprocal_w0 * alpha + reciprocal_w1 * beta + reciprocal_w2 * gamma;
+        apply_fog(
+            fog,
+            color,
+            x as f32 + 0.5,
+            y as f32 + 0.5,
+            1.0 / reciprocal_w,
+            material.blend_mode,
+        )
+    };
+
+    let w0_origin = triangle_cross(&p1, &p2, &p_target) + bias0;
+    let w1_origin = triangle_cross(&p2, &p0, &p_target) + bias1;
+    let w2_origin = triangle_cross(&p0, &p1, &p_target) + bias2;
+    let delta_w0_span = delta_w0_col * EDGE_LANES as i64;
+    let delta_w1_span = delta_w1_col * EDGE_LANES as i64;
+    let delta_w2_span = delta_w2_col * EDGE_LANES as i64;
     let mut is_inside: bool;
 
-    for y in y_min..y_max {
-        let mut w0 = w0_row;
-        let mut w1 = w1_row;
-        let mut w2 = w2_row;
-
-        for x in x_min..x_max {
-            is_inside = w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0;
-            if is_inside {
-                let alpha: f32 = (w0 / triangle_area).to_num();
-                let beta: f32 = (w1 / triangle_area).to_num();
-                let gamma: f32 = (w2 / triangle_area).to_num();
-
-                let interpolated_u: f32 = uv0.u / p0.w.to_num::<f32>() * alpha
-                    + uv1.u / p1.w.to_num::<f32>() * beta
-                    + uv2.u / p2.w.to_num::<f32>() * gamma;
-                let interpolated_v: f32 = uv0.v / p0.w.to_num::<f32>() * alpha
-                    + uv1.v / p1.w.to_num::<f32>() * beta
-                    + uv2.v / p2.w.to_num::<f32>() * gamma;
-                let interpolated_reciprocal_w: f32 = 1.0 / p0.w.to_num::<f32>() * alpha
-                    + 1.0 / p1.w.to_num::<f32>() * beta
-                    + 1.0 / p2.w.to_num::<f32>() * gamma;
-                let u: u32 =
-                    ((interpolated_u / interpolated_reciprocal_w) * texture.width as f32) as u32;
-                let v: u32 =
-                    ((interpolated_v / interpolated_reciprocal_w) * texture.height as f32) as u32;
-                // dbg!(u, v);
-                let index =
-                    (v * texture.width + u) as usize % (texture.width * texture.height) as usize;
-                let w = 1.0 - interpolated_reciprocal_w;
-                let pixel_index = (y as u32 * WIDTH + x as u32) as usize;
-                let texture_color = texture.data[index];
-                let color_after_light = light_apply_intensity(texture_color, light_dot);
-                if pixel_index < get_game_memory().z_buffer.len()
-                    && get_game_memory().z_buffer[pixel_index] > w
-                {
-                    get_game_memory().z_buffer[pixel_index] = w;
-                    render_pixel(x, y, color_after_light);
+    // Depth is monotonic in 1/w, so the vertices bound the depth of every pixel
+    let vertex_depths = [
+        reciprocal_w_to_depth(&depth_state, reciprocal_w0 as f64),
+        reciprocal_w_to_depth(&depth_state, reciprocal_w1 as f64),
+        reciprocal_w_to_depth(&depth_state, reciprocal_w2 as f64),
+    ];
+    let triangle_min_depth = vertex_depths[0].min(vertex_depths[1]).min(vertex_depths[2]);
+    let triangle_max_depth = vertex_depths[0].max(vertex_depths[1]).max(vertex_depths[2]);
+    let use_hierarchical_depth = render_settings.use_hierarchical_depth;
+
+    for block_y in (y_min / HIZ_BLOCK_SIZE)..=((y_max - 1) / HIZ_BLOCK_SIZE) {
+        for block_x in (x_min / HIZ_BLOCK_SIZE)..=((x_max - 1) / HIZ_BLOCK_SIZE) {
+            if use_hierarchical_depth
+                && is_block_occluded(
+                    target,
+                    block_x,
+                    block_y,
+                    triangle_min_depth,
+                    triangle_max_depth,
+                )
+            {
+                frame_stats
+                    .hiz_rejected_blocks
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let block_x_min = max(block_x * HIZ_BLOCK_SIZE, x_min);
+            let block_y_min = max(block_y * HIZ_BLOCK_SIZE, y_min);
+            let block_x_max = min((block_x + 1) * HIZ_BLOCK_SIZE, x_max);
+            let block_y_max = min((block_y + 1) * HIZ_BLOCK_SIZE, y_max);
+            let col_offset = (block_x_min - x_min) as i64;
+            let row_offset = (block_y_min - y_min) as i64;
+            let mut w0_row = w0_origin + delta_w0_col * col_offset + delta_w0_row * row_offset;
+            let mut w1_row = w1_origin + delta_w1_col * col_offset + delta_w1_row * row_offset;
+            let mut w2_row = w2_origin + delta_w2_col * col_offset + delta_w2_row * row_offset;
+            let mut is_depth_written = false;
+
+            for y in block_y_min..block_y_max {
+                let mut w0 = w0_row;
+                let mut w1 = w1_row;
+                let mut w2 = w2_row;
+
+                if samples == 1 {
+                    // Evaluate a span of pixels at once and only shade the covered ones
+                    let mut x = block_x_min;
+                    while x < block_x_max {
+                        let span = evaluate_edge_span(&edge_setup, edge_values(w0, w1, w2));
+                        let lanes = min(EDGE_LANES as i32, block_x_max - x) as usize;
+                        for lane in 0..lanes {
+                            if span.mask & (1 << lane) == 0 {
+                                continue;
+                            }
+                            let color_after_light = shade(
+                                x + lane as i32,
+                                y,
+                                span.alpha[lane],
+                                span.beta[lane],
+                                span.gamma[lane],
+                            );
+                            if !is_alpha_discarded(color_after_light, material.alpha_cutoff) {
+                                is_depth_written |= render_fragment(
+                                    target,
+                                    x + lane as i32,
+                                    y,
+                                    span.depth[lane],
+                                    color_after_light,
+                                    material,
+                                );
+                            }
+                        }
+                        x += EDGE_LANES as i32;
+                        w0 += delta_w0_span;
+                        w1 += delta_w1_span;
+                        w2 += delta_w2_span;
+                    }
+                } else {
+                    for x in block_x_min..block_x_max {
+                        is_inside = w0 >= 0 && w1 >= 0 && w2 >= 0;
+                        let pixel_index =
+                            (((y - target.y_min) * target_width + x) as u32 * samples) as usize;
+                        let mut covered_mask = 0u32;
+                        let mut sample_depths = [0.0f32; MAX_MSAA_SAMPLES];
+                        let mut centroid = (w0, w1, w2);
+                        let mut has_centroid = is_inside;
+                        for i in 0..samples as usize {
+                            let (offset0, offset1, offset2) = sample_offsets[i];
+                            let sample_w0 = w0 + offset0;
+                            let sample_w1 = w1 + offset1;
+                            let sample_w2 = w2 + offset2;
+                            if sample_w0 < 0 || sample_w1 < 0 || sample_w2 < 0 {
+                                continue;
+                            }
+                            if !has_centroid {
+                                centroid = (sample_w0, sample_w1, sample_w2);
+                                has_centroid = true;
+                            }
+                            (_, _, _, sample_depths[i]) = evaluate_edge_point(
+                                &edge_setup,
+                                edge_values(sample_w0, sample_w1, sample_w2),
+                            );
+                            covered_mask |= 1 << i;
+                        }
+
+                        // Cutout fragments have to be shaded before they are allowed to touch depth
+                        let mut shaded_color = None;
+                        let (centroid_alpha, centroid_beta, centroid_gamma, _) =
+                            evaluate_edge_point(
+                                &edge_setup,
+                                edge_values(centroid.0, centroid.1, centroid.2),
+                            );
+                        if covered_mask != 0 && material.alpha_cutoff > 0.0 {
+                            let color_after_light =
+                                shade(x, y, centroid_alpha, centroid_beta, centroid_gamma);
+                            if is_alpha_discarded(color_after_light, material.alpha_cutoff) {
+                                covered_mask = 0;
+                            }
+                            shaded_color = Some(color_after_light);
+                        }
+
+                        let mut passed_mask = 0u32;
+                        for (i, &sample_depth) in
+                            sample_depths.iter().enumerate().take(samples as usize)
+                        {
+                            if covered_mask & (1 << i) != 0
+                                && is_depth_test_passed(
+                                    depth_state.compare,
+                                    sample_depth,
+                                    target.depth[pixel_index + i],
+                                )
+                            {
+                                if material.depth_write {
+                                    target.depth[pixel_index + i] = sample_depth;
+                                    is_depth_written = true;
+                                }
+                                passed_mask |= 1 << i;
+                            }
+                        }
+                        if passed_mask != 0 && target.color_write {
+                            let color_after_light = shaded_color.unwrap_or_else(|| {
+                                shade(x, y, centroid_alpha, centroid_beta, centroid_gamma)
+                            });
+                            for i in 0..samples as usize {
+                                if passed_mask & (1 << i) != 0 {
+                                    target.color[pixel_index + i] = blend_colors(
+                                        color_after_light,
+                                        target.color[pixel_index + i],
+                                        material.blend_mode,
+                                    );
+                                }
+                            }
+                        }
+                        w0 += delta_w0_col;
+                        w1 += delta_w1_col;
+                        w2 += delta_w2_col;
+                    }
                 }
-                // render_pixel(x, y, color);
+                w0_row += delta_w0_row;
+                w1_row += delta_w1_row;
+                w2_row += delta_w2_row;
             }
-            w0 += delta_w0_col;
-            w1 += delta_w1_col;
-            w2 += delta_w2_col;
-        }
-        w0_row += delta_w0_row;
-        w1_row += delta_w1_row;
-        w2_row += delta_w2_row;
-    }
-}
 
-pub fn render_pixel(x_pos: i32, y_pos: i32, color: u32) {
-    let color_buffer = get_color_buffer();
-    let width_i32 = WIDTH as i32;
-    let height_i32 = HEIGHT as i32;
-    if x_pos < width_i32 && x_pos >= 0 && y_pos >= 0 && y_pos < height_i32 {
-        color_buffer[(y_pos * width_i32 + x_pos) as usize] = color;
+            if use_hierarchical_depth && is_depth_written {
+                update_hiz_block(target, block_x, block_y);
+            }
+        }
     }
 }
@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+use std::thread;
+
+use crate::hierarchical_depth::HIZ_BLOCK_SIZE;
+use crate::render::ProjectedTriangle;
+use crate::types::{RenderTarget, ScreenRect, TargetView};
+
+pub const TILE_SIZE: i32 = 64;
+
+pub fn get_tile_rect(tile_index: usize, tiles_x: usize, width: i32, height: i32) -> ScreenRect {
+    let x_min = (tile_index % tiles_x) as i32 * TILE_SIZE;
+    let y_min = (tile_index / tiles_x) as i32 * TILE_SIZE;
+    ScreenRect {
+        x_min,
+        y_min,
+        x_max: (x_min + TILE_SIZE).min(width),
+        y_max: (y_min + TILE_SIZE).min(height),
+    }
+}
+
+pub fn bin_triangles(
+    triangles: &[ProjectedTriangle],
+    tiles_x: usize,
+    tiles_y: usize,
+    width: i32,
+    height: i32,
+) -> Vec<Vec<usize>> {
+    let mut bins: Vec<Vec<usize>> = vec![vec![]; tiles_x * tiles_y];
+    for (triangle_index, projected_triangle) in triangles.iter().enumerate() {
+        let [p0, p1, p2] = projected_triangle.points;
+        // One pixel of slack covers the MSAA sample reach and scanline rounding
+        let x_min = (p0.x.min(p1.x).min(p2.x).floor() as i32 - 1).max(0);
+        let y_min = (p0.y.min(p1.y).min(p2.y).floor() as i32 - 1).max(0);
+        let x_max = (p0.x.max(p1.x).max(p2.x).ceil() as i32 + 1).min(width - 1);
+        let y_max = (p0.y.max(p1.y).max(p2.y).ceil() as i32 + 1).min(height - 1);
+        if x_min > x_max || y_min > y_max {
+            continue;
+        }
+
+        for tile_y in (y_min / TILE_SIZE)..=(y_max / TILE_SIZE) {
+            for tile_x in (x_min / TILE_SIZE)..=(x_max / TILE_SIZE) {
+                bins[tile_y as usize * tiles_x + tile_x as usize].push(triangle_index);
+            }
+        }
+    }
+    bins
+}
+
+// Tile rows start on a coarse depth block row, which keeps their views disjoint.
+// Depth-only targets have no color, so their views get empty color rows
+pub fn split_target_rows(target: &mut RenderTarget) -> Vec<TargetView<'_>> {
+    let width = target.width as usize;
+    let height = target.height as i32;
+    let row_len = TILE_SIZE as usize * width * target.samples as usize;
+    let hiz_row_len = (TILE_SIZE / HIZ_BLOCK_SIZE) as usize * target.hiz_width as usize;
+    let mut color_rows = target.color.chunks_mut(row_len);
+    target
+        .depth
+        .chunks_mut(row_len)
+        .zip(target.hiz.chunks_mut(hiz_row_len))
+        .enumerate()
+        .map(|(tile_y, (depth, hiz))| TargetView {
+            width: target.width,
+            samples: target.samples,
+            y_min: tile_y as i32 * TILE_SIZE,
+            y_max: ((tile_y as i32 + 1) * TILE_SIZE).min(height),
+            color_write: target.color_write,
+            color: color_rows.next().unwrap_or(&mut []),
+            depth,
+            depth_state: target.depth_state,
+            hiz_width: target.hiz_width,
+            hiz,
+        })
+        .collect()
+}
+
+// Each pass picks its rasterizer in `render_triangle`, which runs on the worker threads
+pub fn render_tiles(
+    target: &mut RenderTarget,
+    triangles: &[ProjectedTriangle],
+    render_triangle: impl Fn(&mut TargetView, &ProjectedTriangle, &ScreenRect) + Sync,
+) {
+    let width = target.width as i32;
+    let height = target.height as i32;
+    let tiles_x = ((width + TILE_SIZE - 1) / TILE_SIZE) as usize;
+    let tiles_y = ((height + TILE_SIZE - 1) / TILE_SIZE) as usize;
+
+    // Bins keep submission order, so each tile still blends its triangles back to front
+    let bins = bin_triangles(triangles, tiles_x, tiles_y, width, height);
+    let num_threads = thread::available_parallelism()
+        .map(|p| p.get())
+        .unwrap_or(4)
+        .min(tiles_y);
+    // Workers take a whole row of tiles at a time, since only full rows are contiguous
+    let rows = Mutex::new(split_target_rows(target).into_iter().enumerate());
+
+    thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| loop {
+                let Some((tile_y, mut row)) = rows.lock().unwrap().next() else {
+                    break;
+                };
+                for tile_x in 0..tiles_x {
+                    let tile_index = tile_y * tiles_x + tile_x;
+                    let clip = get_tile_rect(tile_index, tiles_x, width, height);
+                    for &triangle_index in &bins[tile_index] {
+                        render_triangle(&mut row, &triangles[triangle_index], &clip);
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::make_varyings;
+    use crate::types::Vec3;
+    use crate::vector::Vec4;
+
+    const WIDTH: i32 = 256;
+    const HEIGHT: i32 = 192;
+    const TILES_X: usize = 4;
+    const TILES_Y: usize = 3;
+
+    fn get_triangle(points: [(f32, f32); 3]) -> ProjectedTriangle {
+        ProjectedTriangle {
+            points: points.map(|(x, y)| Vec4 {
+                x,
+                y,
+                z: 1.0,
+                w: 1.0,
+            }),
+            varyings: [make_varyings(&[]); 3],
+            normal: Vec3::default(),
+            normal_avg: Vec3::default(),
+            depth: 1.0,
+            entity_index: 0,
+        }
+    }
+
+    fn get_binned_tiles(bins: &[Vec<usize>], triangle_index: usize) -> Vec<usize> {
+        bins.iter()
+            .enumerate()
+            .filter(|(_, bin)| bin.contains(&triangle_index))
+            .map(|(tile_index, _)| tile_index)
+            .collect()
+    }
+
+    #[test]
+    fn triangle_lands_in_every_tile_its_bounds_touch() {
+        let triangles = [
+            get_triangle([(10.0, 10.0), (40.0, 10.0), (10.0, 40.0)]),
+            // Crosses the first vertical and horizontal tile edges
+            get_triangle([(50.0, 50.0), (100.0, 50.0), (50.0, 100.0)]),
+        ];
+        let bins = bin_triangles(&triangles, TILES_X, TILES_Y, WIDTH, HEIGHT);
+        assert_eq!(get_binned_tiles(&bins, 0), [0]);
+        assert_eq!(get_binned_tiles(&bins, 1), [0, 1, 4, 5]);
+    }
+
+    #[test]
+    fn bins_keep_submission_order() {
+        let triangles = [
+            get_triangle([(10.0, 10.0), (200.0, 10.0), (10.0, 150.0)]),
+            get_triangle([(20.0, 20.0), (30.0, 20.0), (20.0, 30.0)]),
+            get_triangle([(5.0, 5.0), (60.0, 5.0), (5.0, 60.0)]),
+        ];
+        let bins = bin_triangles(&triangles, TILES_X, TILES_Y, WIDTH, HEIGHT);
+        assert_eq!(bins[0], [0, 1, 2]);
+    }
+
+    #[test]
+    fn triangles_off_the_target_are_dropped() {
+        let triangles = [
+            get_triangle([(-50.0, -50.0), (-20.0, -50.0), (-50.0, -20.0)]),
+            get_triangle([(300.0, 10.0), (400.0, 10.0), (300.0, 50.0)]),
+        ];
+        let bins = bin_triangles(&triangles, TILES_X, TILES_Y, WIDTH, HEIGHT);
+        assert!(bins.iter().all(|bin| bin.is_empty()));
+    }
+
+    #[test]
+    fn bounds_are_clamped_to_the_last_tiles() {
+        let triangles = [get_triangle([
+            (200.0, 150.0),
+            (1000.0, 150.0),
+            (200.0, 1000.0),
+        ])];
+        let bins = bin_triangles(&triangles, TILES_X, TILES_Y, WIDTH, HEIGHT);
+        assert_eq!(get_binned_tiles(&bins, 0), [TILES_X * TILES_Y - 1]);
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::atomic::AtomicU32;
+
 #[derive(Copy, Clone)]
 pub struct Vec2 {
     pub x: f32,
@@ -56,10 +58,83 @@ impl From<Vec2> for IntVec2 {
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextureAddressMode {
+    Repeat,
+    ClampToEdge,
+    MirroredRepeat,
+    Border,
+}
+
+// Texels are linear RGBA, color images are decoded from sRGB when they are loaded
 pub struct Texture {
-    pub data: Vec<u32>,
+    pub data: Vec<[f32; 4]>,
     pub width: u32,
     pub height: u32,
+    pub address_mode_u: TextureAddressMode,
+    pub address_mode_v: TextureAddressMode,
+    // sRGB, like every other packed color
+    pub border_color: u32,
+}
+
+// Cube faces are ordered +x, -x, +y, -y, +z, -z
+pub enum Environment {
+    CubeMap([Texture; 6]),
+    Panorama(Texture),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    Opaque,
+    Alpha,
+    Additive,
+    Multiply,
+    PremultipliedAlpha,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ShadingMode {
+    Flat,
+    Gouraud,
+    Phong,
+}
+
+pub struct Material {
+    pub texture: Texture,
+    pub normal_map: Option<Texture>,
+    pub shading_mode: ShadingMode,
+    pub specular_color: u32,
+    pub shininess: f32,
+    // Share of the environment mixed into per-pixel lit surfaces
+    pub reflectivity: f32,
+    pub blend_mode: BlendMode,
+    pub opacity: f32,
+    pub depth_write: bool,
+    pub alpha_cutoff: f32,
+    // UV set the texture and normal map sample, generated tangents follow set 0
+    pub uv_set: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LightType {
+    Directional,
+    Point,
+    Spot,
+}
+
+// Position and direction are in world space, cone angles are half angles in radians
+#[derive(Clone, Copy)]
+pub struct Light {
+    pub light_type: LightType,
+    pub enabled: bool,
+    pub casts_shadows: bool,
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub color: u32,
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_cone: f32,
+    pub outer_cone: f32,
 }
 
 pub struct Camera {
@@ -74,14 +149,73 @@ pub struct Plane {
     pub normal_dirrection: Vec3,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum AntiAliasing {
+    None,
+    Ssaa,
+    Msaa,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DepthFormat {
+    LinearView,
+    ReciprocalW,
+    Ndc,
+    ReverseZ,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DepthCompare {
+    Less,
+    LessEqual,
+    Greater,
+    Always,
+}
+
+#[derive(Clone, Copy)]
+pub struct DepthState {
+    pub format: DepthFormat,
+    pub compare: DepthCompare,
+    pub scale: f64,
+    pub offset: f64,
+    pub clear_value: f32,
+}
+
 pub struct RenderSettings {
     pub show_normals: bool,
     pub fill_triangles: bool,
     pub use_textures: bool,
+    pub use_scanline_rasterizer: bool,
     pub draw_vert: bool,
     pub draw_edges: bool,
     pub use_lighting: bool,
     pub default_render_color: u32,
+    pub anti_aliasing: AntiAliasing,
+    pub aa_samples: u32,
+    pub use_fxaa: bool,
+    pub antialias_lines: bool,
+    pub line_width: f32,
+    pub depth_test_overlays: bool,
+    pub overlay_depth_bias: f32,
+    pub hidden_line: bool,
+    pub use_hierarchical_depth: bool,
+    pub subpixel_bits: u32,
+    pub depth_format: DepthFormat,
+    pub depth_compare: DepthCompare,
+    pub use_shadows: bool,
+    pub shadow_map_size: u32,
+    pub shadow_depth_bias: f32,
+    pub shadow_slope_bias: f32,
+    pub shadow_pcf_radius: u32,
+    pub use_skybox: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FogMode {
+    None,
+    Linear,
+    Exponential,
+    ExponentialSquared,
 }
 pub struct ViewSettings {
     pub planes: Vec<Plane>,
@@ -90,21 +224,73 @@ pub struct ViewSettings {
     pub fov: Vec2,
     pub width: u32,
     pub height: u32,
+    pub fog_mode: FogMode,
+    pub fog_color: u32,
+    // Fades toward the environment behind each fragment instead of the fog color
+    pub fog_use_sky_color: bool,
+    // Linear fog starts at fog_start and is full at fog_end, the exponential modes use the density
+    pub fog_start: f32,
+    pub fog_end: f32,
+    pub fog_density: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct ScreenRect {
+    pub x_min: i32,
+    pub y_min: i32,
+    pub x_max: i32,
+    pub y_max: i32,
+}
+
+pub struct RenderTarget {
+    pub width: u32,
+    pub height: u32,
+    pub scale_x: u32,
+    pub scale_y: u32,
+    pub samples: u32,
+    pub color_write: bool,
+    pub color: Vec<u32>,
+    pub depth: Vec<f32>,
+    pub depth_state: DepthState,
+    pub hiz_width: u32,
+    pub hiz: Vec<f32>,
+}
+
+// Rows y_min..y_max of a render target, with the coarse depth rows that cover them.
+// Views never overlap, so rasterizer threads can write them side by side
+pub struct TargetView<'a> {
+    pub width: u32,
+    pub samples: u32,
+    pub y_min: i32,
+    pub y_max: i32,
+    pub color_write: bool,
+    pub color: &'a mut [u32],
+    pub depth: &'a mut [f32],
+    pub depth_state: DepthState,
+    pub hiz_width: u32,
+    pub hiz: &'a mut [f32],
+}
+
+pub struct FrameStats {
+    pub hiz_rejected_blocks: AtomicU32,
 }
 
 pub struct Memory {
     pub delta_time: f32,
     pub color_buffer: Vec<u32>,
-    pub entity: Entity,
+    pub entities: Vec<Entity>,
     pub camera: Camera,
     pub rotation_objects_type: u32,
     pub speed: f32,
     pub stop: bool,
     pub render_settings: RenderSettings,
-    pub light: Vec3,
-    pub texture: Texture,
-    pub z_buffer: Vec<f32>,
+    pub lights: Vec<Light>,
+    pub selected_light: usize,
+    pub ambient_light: f32,
+    pub environment: Environment,
+    pub target: RenderTarget,
     pub view_settings: ViewSettings,
+    pub frame_stats: FrameStats,
 }
 
 #[derive(Clone, Copy)]
@@ -133,6 +319,7 @@ impl From<Vec2> for TextureUV {
 
 pub struct Entity {
     pub mesh: Mesh,
+    pub material: Material,
     pub rotation: Vec3,
     pub scale: Vec3,
     pub translation: Vec3,
@@ -147,7 +334,25 @@ pub struct Triangle {
     pub c_uv: TextureUV,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum VertexSemantic {
+    Normal,
+    Color,
+    Tangent,
+    // UV sets past the first one, which lives on the triangles
+    TexCoord(u8),
+}
+
+// Indexed like OBJ data, each triangle corner points at its own element of the stream
+pub struct VertexAttribute {
+    pub semantic: VertexSemantic,
+    pub components: usize,
+    pub values: Vec<f32>,
+    pub indices: Vec<[u32; 3]>,
+}
+
 pub struct Mesh {
     pub vertices: Vec<Vec3>,
     pub triangles: Vec<Triangle>,
+    pub attributes: Vec<VertexAttribute>,
 }
@@ -1,7 +1,4 @@
 use crate::types::{IntVec2, Vec2, Vec3};
-use fixed::types::extra::U16;
-use fixed::FixedI64;
-type Fixed = FixedI64<U16>;
 
 #[derive(Copy, Clone)]
 pub struct Vec4 {
@@ -11,11 +8,12 @@ pub struct Vec4 {
     pub w: f32,
 }
 
-pub struct FixedVec4 {
-    pub x: Fixed,
-    pub y: Fixed,
-    pub z: Fixed,
-    pub w: Fixed,
+// Screen position snapped to a grid of 1 / 2^subpixel_bits of a pixel
+pub struct SubpixelVec4 {
+    pub x: i64,
+    pub y: i64,
+    pub z: f32,
+    pub w: f32,
 }
 
 pub fn vector4_trunk(from: Vec4) -> Vec4 {
@@ -48,14 +46,13 @@ impl From<Vec4> for Vec3 {
     }
 }
 
-impl From<Vec4> for FixedVec4 {
-    fn from(v: Vec4) -> Self {
-        FixedVec4 {
-            x: Fixed::from_num(v.x),
-            y: Fixed::from_num(v.y),
-            z: Fixed::from_num(v.z),
-            w: Fixed::from_num(v.w),
-        }
+pub fn vector4_snap(from: Vec4, subpixel_bits: u32) -> SubpixelVec4 {
+    let grid = (1i64 << subpixel_bits) as f64;
+    SubpixelVec4 {
+        x: (from.x as f64 * grid).round() as i64,
+        y: (from.y as f64 * grid).round() as i64,
+        z: from.z,
+        w: from.w,
     }
 }
 